@@ -0,0 +1,234 @@
+use crate::client::WhipClaims;
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use jwt::{SignWithKey, VerifyWithKey};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    // Missing, malformed or unverifiable credentials, 401
+    Unauthorized,
+    // Valid credentials that don't grant this request, 403
+    Forbidden,
+}
+
+// Validates WHIP bearer tokens, either static tokens or HMAC signed JWTs
+pub struct Authenticator {
+    tokens: Vec<String>,
+    key: Option<Hmac<Sha256>>,
+    // jti and expiry of every unexpired JWT accepted so far, each may only
+    // be used once
+    used_jti: Mutex<HashMap<String, u64>>,
+}
+
+impl Authenticator {
+    pub fn new(tokens: Vec<String>, secret: Option<String>) -> Result<Self> {
+        let key = secret
+            .map(|s| Hmac::new_from_slice(s.as_bytes()))
+            .transpose()
+            .map_err(|e| anyhow!("invalid jwt secret: {e}"))?;
+        Ok(Self {
+            tokens,
+            key,
+            used_jti: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // No credentials configured, every request is accepted
+    pub fn is_open(&self) -> bool {
+        self.tokens.is_empty() && self.key.is_none()
+    }
+
+    // `path` is the WHIP endpoint path, JWTs must be issued for it
+    pub fn check(&self, authorization: Option<&str>, path: &str) -> Result<(), AuthError> {
+        if self.is_open() {
+            return Ok(());
+        }
+
+        let token = authorization
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or(AuthError::Unauthorized)?;
+
+        if self.tokens.iter().any(|t| constant_time_eq(t, token)) {
+            return Ok(());
+        }
+
+        let key = self.key.as_ref().ok_or(AuthError::Unauthorized)?;
+        let claims: WhipClaims = token.verify_with_key(key).map_err(|e| {
+            info!("rejected token: {e}");
+            AuthError::Unauthorized
+        })?;
+
+        let now = unix_time();
+        if claims.exp <= now {
+            info!("token expired, jti {}", claims.jti);
+            return Err(AuthError::Unauthorized);
+        }
+
+        // Compare paths only, so the server can sit behind a reverse proxy
+        let claim_path = url::Url::parse(&claims.whip_url)
+            .map(|u| u.path().to_string())
+            .map_err(|_| AuthError::Forbidden)?;
+        if claim_path.trim_end_matches('/') != path.trim_end_matches('/') {
+            info!("token issued for {}, not {path}", claims.whip_url);
+            return Err(AuthError::Forbidden);
+        }
+
+        let mut used_jti = self.used_jti.lock().unwrap();
+        // Expired tokens are refused anyway, their jtis needn't be kept
+        used_jti.retain(|_, exp| *exp > now);
+        if used_jti.insert(claims.jti.clone(), claims.exp).is_some() {
+            info!("token replayed, jti {}", claims.jti);
+            return Err(AuthError::Forbidden);
+        }
+
+        Ok(())
+    }
+}
+
+pub fn mint(secret: &str, claims: &WhipClaims) -> Result<String> {
+    let key: Hmac<Sha256> =
+        Hmac::new_from_slice(secret.as_bytes()).map_err(|e| anyhow!("invalid jwt secret: {e}"))?;
+    claims
+        .sign_with_key(&key)
+        .map_err(|e| anyhow!("failed to sign token: {e}"))
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Compares digests so neither a token's contents nor its length show in the
+// time taken
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+    const PATH: &str = "/whip";
+
+    fn jwt(secret: &str, whip_url: &str, jti: &str, exp: u64) -> String {
+        let claims = WhipClaims {
+            whip_url: whip_url.to_string(),
+            jti: jti.to_string(),
+            exp,
+        };
+        format!("Bearer {}", mint(secret, &claims).unwrap())
+    }
+
+    fn valid_jwt(jti: &str) -> String {
+        jwt(SECRET, "http://example.com/whip", jti, unix_time() + 60)
+    }
+
+    fn jwt_auth() -> Authenticator {
+        Authenticator::new(vec![], Some(SECRET.to_string())).unwrap()
+    }
+
+    #[test]
+    fn open() {
+        let auth = Authenticator::new(vec![], None).unwrap();
+        assert_eq!(auth.check(None, PATH), Ok(()));
+    }
+
+    #[test]
+    fn static_token() {
+        let auth = Authenticator::new(vec!["token".to_string()], None).unwrap();
+        assert_eq!(auth.check(Some("Bearer token"), PATH), Ok(()));
+        // Static tokens aren't tied to a path
+        assert_eq!(auth.check(Some("Bearer token"), "/other"), Ok(()));
+    }
+
+    #[test]
+    fn wrong_token() {
+        let auth = Authenticator::new(vec!["token".to_string()], None).unwrap();
+        for authorization in [
+            None,
+            Some("Bearer wrong"),
+            Some("Bearer tok"),
+            Some("token"),
+        ] {
+            assert_eq!(
+                auth.check(authorization, PATH),
+                Err(AuthError::Unauthorized)
+            );
+        }
+    }
+
+    #[test]
+    fn jwt_accepted() {
+        let auth = jwt_auth();
+        assert_eq!(auth.check(Some(&valid_jwt("a")), PATH), Ok(()));
+        // Behind a proxy the host and a trailing slash may differ
+        let token = jwt(
+            SECRET,
+            "https://proxy.example.net/whip/",
+            "b",
+            unix_time() + 60,
+        );
+        assert_eq!(auth.check(Some(&token), PATH), Ok(()));
+    }
+
+    #[test]
+    fn bad_signature() {
+        let auth = jwt_auth();
+        let token = jwt("other", "http://example.com/whip", "a", unix_time() + 60);
+        assert_eq!(auth.check(Some(&token), PATH), Err(AuthError::Unauthorized));
+    }
+
+    #[test]
+    fn path_mismatch() {
+        let auth = jwt_auth();
+        let token = jwt(SECRET, "http://example.com/other", "a", unix_time() + 60);
+        assert_eq!(auth.check(Some(&token), PATH), Err(AuthError::Forbidden));
+    }
+
+    #[test]
+    fn replayed_jti() {
+        let auth = jwt_auth();
+        let token = valid_jwt("a");
+        assert_eq!(auth.check(Some(&token), PATH), Ok(()));
+        assert_eq!(auth.check(Some(&token), PATH), Err(AuthError::Forbidden));
+    }
+
+    #[test]
+    fn expired() {
+        let auth = jwt_auth();
+        let token = jwt(SECRET, "http://example.com/whip", "a", unix_time() - 1);
+        assert_eq!(auth.check(Some(&token), PATH), Err(AuthError::Unauthorized));
+    }
+
+    #[test]
+    fn expired_jti_pruned() {
+        let auth = jwt_auth();
+        auth.used_jti
+            .lock()
+            .unwrap()
+            .insert("old".to_string(), unix_time() - 1);
+        assert_eq!(auth.check(Some(&valid_jwt("new")), PATH), Ok(()));
+
+        let used_jti = auth.used_jti.lock().unwrap();
+        assert!(!used_jti.contains_key("old"));
+        assert!(used_jti.contains_key("new"));
+    }
+
+    #[test]
+    fn constant_time_eq_lengths() {
+        assert!(constant_time_eq("token", "token"));
+        assert!(!constant_time_eq("token", "tokens"));
+        assert!(!constant_time_eq("", "token"));
+    }
+}
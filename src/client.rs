@@ -1,12 +1,25 @@
+use crate::{
+    ice_server::{self, IceServer},
+    ice_tcp::{IceTcp, TcpPacket},
+    sdpfrag::{self, SdpFragment},
+    stun,
+    turn::TurnClient,
+    NetworkConfig,
+};
 use bytes::Bytes;
 use local_ip_address::list_afinet_netifas;
-use reqwest::header::{HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, USER_AGENT};
-use serde::Deserialize;
+use reqwest::{
+    header::{HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, IF_MATCH, USER_AGENT},
+    Method, StatusCode,
+};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     error::Error,
     io::ErrorKind,
-    net::{IpAddr, SocketAddr, SocketAddrV4},
+    net::{IpAddr, SocketAddr},
     str::FromStr,
+    task::Poll,
     time::{Duration, Instant},
 };
 use str0m::{
@@ -14,21 +27,26 @@ use str0m::{
     format::Codec,
     media::{Direction as RtcDirection, MediaData, MediaKind, MediaTime, Mid},
     net::{Protocol, Receive},
+    stats::{MediaIngressStats, PeerStats},
     Candidate, Event, IceConnectionState, Input, Output, Rtc,
 };
-use tokio::net::UdpSocket;
+use tokio::{io::ReadBuf, net::UdpSocket};
 use tracing::{debug, error, info, trace, warn};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct WhipClaims {
     pub whip_url: String,
     pub jti: String,
+    // Expiry, seconds since the Unix epoch
+    pub exp: u64,
 }
 
 #[derive(Debug)]
 pub enum WebrtcEvent {
     Continue,
     Media(MediaData),
+    IngressStats(MediaIngressStats),
+    PeerStats(PeerStats),
     Disconnected,
 }
 
@@ -44,65 +62,259 @@ pub enum WebrtcError {
 
 pub struct Client {
     rtc: Rtc,
-    socket: UdpSocket,
-    local_socket_addr: SocketAddr,
+    // One per host candidate, bound to its address
+    sockets: Vec<UdpSocket>,
+    network: NetworkConfig,
     buf: [u8; 1500],
     video_mid: Option<Mid>,
-    _audio_mid: Option<Mid>,
+    audio_mid: Option<Mid>,
+    // WHIP/WHEP session resource from the Location header, and the http
+    // client (with auth headers) used to create it
+    resource_url: Option<reqwest::Url>,
+    http: Option<reqwest::Client>,
+    // Entity tag of the resource, sent as If-Match on PATCH
+    etag: Option<String>,
+    // Our ICE credentials and bundle tag media section from the offer,
+    // sdpfrags must carry them
+    local_frag: SdpFragment,
+    // Last full SDP from the remote, ICE restarts patch new credentials into it
+    remote_sdp: Option<String>,
+    // Cleared once the server rejects PATCH, candidates are then not trickled
+    trickle_supported: bool,
+    ice_state: IceConnectionState,
+    // STUN/TURN servers from Link headers on the endpoint
+    ice_servers: Vec<IceServer>,
+    // Relay allocation, transmits from its address are tunnelled through it
+    turn: Option<TurnClient>,
+    // ICE-TCP connections, unless disabled
+    tcp: Option<IceTcp>,
+}
+
+enum Received {
+    // Length, source and the local address it arrived on
+    Socket(usize, SocketAddr, SocketAddr),
+    Relay(SocketAddr, Vec<u8>),
+    Tcp(TcpPacket),
+    // TURN responses and the like, nothing for str0m
+    Control,
+}
+
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+
+// OPTIONS on the endpoint, answered with its ICE servers by WHIP servers
+// that support it. Anything else just means there are none
+async fn preflight(http: &reqwest::Client, url: &reqwest::Url) -> Vec<IceServer> {
+    match http.request(Method::OPTIONS, url.clone()).send().await {
+        Ok(res) if res.status().is_success() => {
+            let servers = ice_server::from_headers(res.headers());
+            info!("ice servers: {:?}", servers);
+            servers
+        }
+        Ok(res) => {
+            debug!("OPTIONS not supported: {}", res.status());
+            vec![]
+        }
+        Err(e) => {
+            debug!("OPTIONS failed: {:?}", e);
+            vec![]
+        }
+    }
+}
+
+// Interface addresses we gather host candidates on, after the --interface,
+// --exclude-interface, loopback, link-local and IPv6 filters
+fn interface_ips(network: &NetworkConfig) -> Result<Vec<IpAddr>, WebrtcError> {
+    let Ok(network_interfaces) = list_afinet_netifas() else {
+        return Err(WebrtcError::NoCandidates);
+    };
+
+    let mut ips = vec![];
+    for (name, ip) in network_interfaces {
+        let link_local = match ip {
+            IpAddr::V4(ip4) => ip4.is_link_local(),
+            IpAddr::V6(ip6) => ip6.segments()[0] & 0xffc0 == 0xfe80,
+        };
+        let included = (network.interfaces.is_empty() || network.interfaces.contains(&name))
+            && !network.exclude_interfaces.contains(&name)
+            && !(ip.is_loopback() && network.exclude_loopback)
+            && !(ip.is_ipv6() && network.no_ipv6)
+            // IPv6 link-local would need a scope id to bind to
+            && !(link_local && (ip.is_ipv6() || !network.include_link_local));
+
+        info!(
+            "iface: {} / {:?}{}",
+            name,
+            ip,
+            if included { "" } else { " (skipped)" }
+        );
+        if included && !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+    Ok(ips)
+}
+
+// A socket bound to each address, so we know which address traffic arrives
+// on and can send from the one str0m picked
+async fn bind_host_sockets(ips: &[IpAddr]) -> Vec<UdpSocket> {
+    let mut sockets = vec![];
+    for ip in ips {
+        match UdpSocket::bind(SocketAddr::new(*ip, 0)).await {
+            Ok(socket) => sockets.push(socket),
+            Err(e) => warn!("failed to bind udp socket on {}: {:?}", ip, e),
+        }
+    }
+    sockets
+}
+
+fn host_candidate(socket: &UdpSocket) -> Option<Candidate> {
+    let addr = socket.local_addr().ok()?;
+    match Candidate::host(addr, Protocol::Udp) {
+        Ok(candidate) => Some(candidate),
+        Err(e) => {
+            warn!("invalid host candidate {}: {:?}", addr, e);
+            None
+        }
+    }
+}
+
+// Next relayed datagram, never if we have no relay
+async fn recv_relay(turn: &mut Option<TurnClient>) -> std::io::Result<Received> {
+    let Some(turn) = turn else {
+        return std::future::pending().await;
+    };
+    Ok(match turn.recv().await? {
+        Some((peer, data)) => Received::Relay(peer, data),
+        None => Received::Control,
+    })
+}
+
+async fn recv_tcp(tcp: &mut Option<IceTcp>) -> TcpPacket {
+    match tcp {
+        Some(tcp) => tcp.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Passive host candidate on one of our listeners. Priority follows RFC 6544
+// section 4.2, with a type preference below UDP host and server reflexive
+// candidates and the passive direction preference in the local preference
+fn tcp_candidate((index, addr): (usize, &SocketAddr)) -> Option<Candidate> {
+    let priority = (90 << 24) | (4 << 13 << 8) | 255;
+    // Foundations only need to differ between base addresses
+    let sdp = format!(
+        "candidate:tcp{} 1 tcp {} {} {} typ host tcptype passive",
+        index,
+        priority,
+        addr.ip(),
+        addr.port()
+    );
+    match Candidate::from_sdp_string(&sdp) {
+        Ok(candidate) => Some(candidate),
+        Err(e) => {
+            warn!("invalid tcp candidate {}: {:?}", sdp, e);
+            None
+        }
+    }
+}
+
+fn tcp_candidates(tcp: &Option<IceTcp>) -> Vec<Candidate> {
+    let Some(tcp) = tcp else {
+        return vec![];
+    };
+    tcp.listeners()
+        .iter()
+        .enumerate()
+        .filter_map(tcp_candidate)
+        .collect()
+}
+
+// str0m writes candidates without their tcptype, add it back to ours, which
+// are all passive. Works on whole SDPs or single candidate lines
+fn with_tcptype(sdp: &str) -> String {
+    sdp.split_inclusive('\n')
+        .map(|line| {
+            let tcp = line.contains("candidate:")
+                && line
+                    .split(' ')
+                    .nth(2)
+                    .is_some_and(|proto| proto.eq_ignore_ascii_case("tcp"));
+            if tcp && !line.contains(" tcptype ") {
+                line.replacen(" typ host", " typ host tcptype passive", 1)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect()
+}
+
+// First datagram on any of the sockets, with the address it arrived on
+async fn recv_any(
+    sockets: &[UdpSocket],
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, SocketAddr)> {
+    std::future::poll_fn(|cx| {
+        for socket in sockets {
+            let mut read = ReadBuf::new(buf);
+            if let Poll::Ready(result) = socket.poll_recv_from(cx, &mut read) {
+                let destination = socket.local_addr()?;
+                return Poll::Ready(
+                    result.map(|source| (read.filled().len(), source, destination)),
+                );
+            }
+        }
+        Poll::Pending
+    })
+    .await
 }
 
 impl Client {
-    pub async fn new() -> Result<Self, WebrtcError> {
-        let socket = UdpSocket::bind("0.0.0.0:0".parse::<SocketAddrV4>().unwrap())
-            .await
-            .expect("Should bind udp socket");
+    pub async fn new(network: &NetworkConfig) -> Result<Self, WebrtcError> {
+        let ips = interface_ips(network)?;
+        let sockets = bind_host_sockets(&ips).await;
+        if sockets.is_empty() {
+            return Err(WebrtcError::NoCandidates);
+        }
+        // TCP candidates for peers that can't reach us over UDP
+        let tcp = match network.no_ice_tcp {
+            true => None,
+            false => Some(IceTcp::bind(&ips).await),
+        };
 
         let mut rtc = Rtc::builder()
             .clear_codecs()
             .enable_h264(true)
+            .enable_opus(true)
             .set_stats_interval(Some(Duration::from_secs(2)))
             .set_reordering_size_video(1)
             .set_reordering_size_audio(1)
             .build();
 
-        info!("local socket address: {:?}", socket.local_addr());
-
-        // Discover host candidates
-        let mut local_socket_addr = None;
-        if let Ok(network_interfaces) = list_afinet_netifas() {
-            for (name, ip) in network_interfaces {
-                info!("iface: {} / {:?}", name, ip);
-                match ip {
-                    IpAddr::V4(ip4) => {
-                        //if !ip4.is_loopback() && !ip4.is_link_local() {
-                        if !ip4.is_link_local() {
-                            let socket_addr =
-                                SocketAddr::new(ip, socket.local_addr().unwrap().port());
-                            local_socket_addr = Some(socket_addr.clone());
-                            rtc.add_local_candidate(
-                                Candidate::host(socket_addr, str0m::net::Protocol::Udp)
-                                    .expect("Failed to create local candidate"),
-                            );
-                        }
-                    }
-                    IpAddr::V6(_ip6) => {}
-                }
-            }
-        } else {
-            return Err(WebrtcError::NoCandidates);
+        for candidate in sockets.iter().filter_map(host_candidate) {
+            info!("local socket address: {}", candidate.addr());
+            rtc.add_local_candidate(candidate);
+        }
+        for candidate in tcp_candidates(&tcp) {
+            rtc.add_local_candidate(candidate);
         }
-
-        let Some(local_socket_addr) = local_socket_addr else {
-            return Err(WebrtcError::NoCandidates);
-        };
 
         Ok(Self {
-            socket,
-            local_socket_addr,
+            sockets,
+            network: network.clone(),
             rtc,
             buf: [0; 1500],
             video_mid: None,
-            _audio_mid: None,
+            audio_mid: None,
+            resource_url: None,
+            http: None,
+            etag: None,
+            local_frag: SdpFragment::default(),
+            remote_sdp: None,
+            trickle_supported: true,
+            ice_state: IceConnectionState::New,
+            ice_servers: network.ice_servers(),
+            turn: None,
+            tcp,
         })
     }
 
@@ -111,20 +323,8 @@ impl Client {
         url: &str,
         token: &Option<String>,
         direction: RtcDirection,
+        audio: bool,
     ) -> Result<(), WebrtcError> {
-        // Add receive tracks and generate an offer
-        let mut change = self.rtc.sdp_api();
-        self.video_mid = Some(change.add_media(
-            MediaKind::Video,
-            direction,
-            Some("video_0".to_string()),
-            Some("video_0".to_string()),
-        ));
-
-        let (offer, pending) = change.apply().ok_or(WebrtcError::SdpError)?;
-
-        let offer_str = offer.to_sdp_string();
-        info!("offer: {}", offer_str);
         info!("token: {:?}", token);
         info!("url: {}", url);
 
@@ -151,6 +351,35 @@ impl Client {
 
         let mut next_url =
             reqwest::Url::from_str(&url).map_err(|e| WebrtcError::ServerError(e.into()))?;
+
+        // Servers may advertise ICE servers ahead of the offer, so reflexive
+        // candidates can go in it rather than being trickled
+        self.ice_servers.extend(preflight(&client, &next_url).await);
+        self.gather_configured().await;
+
+        // Add receive tracks and generate an offer
+        let mut change = self.rtc.sdp_api();
+        self.video_mid = Some(change.add_media(
+            MediaKind::Video,
+            direction,
+            Some("video_0".to_string()),
+            Some("video_0".to_string()),
+        ));
+        if audio {
+            self.audio_mid = Some(change.add_media(
+                MediaKind::Audio,
+                direction,
+                Some("audio_0".to_string()),
+                Some("audio_0".to_string()),
+            ));
+        }
+
+        let (offer, pending) = change.apply().ok_or(WebrtcError::SdpError)?;
+
+        let offer_str = with_tcptype(&offer.to_sdp_string());
+        info!("offer: {}", offer_str);
+        self.local_frag = SdpFragment::parse(&offer_str);
+
         let res = loop {
             let response = client
                 .post(next_url.clone())
@@ -187,6 +416,25 @@ impl Client {
         }
 
         info!("headers: {:?}", res.headers());
+        // Location may be relative to the endpoint
+        self.resource_url = res
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| next_url.join(l).ok());
+        info!("resource url: {:?}", self.resource_url);
+        self.etag = res
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|e| e.to_str().ok())
+            .map(str::to_string);
+
+        // Anything advertised only on the answer is gathered late and trickled
+        let late_servers: Vec<_> = ice_server::from_headers(res.headers())
+            .into_iter()
+            .filter(|server| !self.ice_servers.contains(server))
+            .collect();
+
         let answer = res
             .text()
             .await
@@ -200,18 +448,389 @@ impl Client {
             )
             .map_err(|_| WebrtcError::SdpError)?;
 
+        self.remote_sdp = Some(answer);
+        self.http = Some(client);
+
+        if !late_servers.is_empty() {
+            let mut candidates = self.gather_srflx(&late_servers).await;
+            candidates.extend(self.gather_relay(&late_servers).await);
+            self.ice_servers.extend(late_servers);
+            if !candidates.is_empty() {
+                self.trickle_candidates(candidates, false).await?;
+            }
+        }
         Ok(())
     }
 
+    // Reflexive and relay candidates from the ICE servers we know of, added
+    // before the offer or answer is created so they go in it
+    pub async fn gather_configured(&mut self) {
+        let servers = self.ice_servers.clone();
+        let srflx = self.gather_srflx(&servers).await;
+        let relay = self.gather_relay(&servers).await;
+        for candidate in srflx.into_iter().chain(relay) {
+            self.rtc.add_local_candidate(candidate);
+        }
+    }
+
+    // Allocate a relay on the first TURN server that lets us, one is enough
+    async fn gather_relay(&mut self, servers: &[IceServer]) -> Option<Candidate> {
+        if self.turn.is_some() {
+            return None;
+        }
+        for server in servers.iter().filter(|s| s.is_turn()) {
+            match TurnClient::allocate(server).await {
+                Ok(turn) => {
+                    self.turn = Some(turn);
+                    return self.relay_candidate();
+                }
+                Err(e) => warn!("turn allocation on {} failed: {:?}", server.url, e),
+            }
+        }
+        None
+    }
+
+    fn relay_candidate(&self) -> Option<Candidate> {
+        let relayed = self.turn.as_ref()?.relayed_addr();
+        // The relayed address is its own base, so str0m transmits from it
+        // and we know to tunnel those through the server
+        match Candidate::relayed(relayed, Protocol::Udp) {
+            Ok(candidate) => Some(candidate),
+            Err(e) => {
+                warn!("invalid relay candidate {}: {:?}", relayed, e);
+                None
+            }
+        }
+    }
+
+    // Learn our public addresses from each STUN server with binding requests
+    // sent from every media socket, giving server reflexive candidates
+    async fn gather_srflx(&mut self, servers: &[IceServer]) -> Vec<Candidate> {
+        let mut pending = HashMap::new();
+        for server in servers.iter().filter(|s| s.is_stun()) {
+            let Some(host_port) = server.host_port() else {
+                warn!("invalid stun server: {}", server.url);
+                continue;
+            };
+            let Ok(addrs) = tokio::net::lookup_host(&host_port).await else {
+                warn!("failed to resolve stun server: {}", host_port);
+                continue;
+            };
+            let addrs: Vec<SocketAddr> = addrs.collect();
+
+            for socket in &self.sockets {
+                let Ok(base) = socket.local_addr() else {
+                    continue;
+                };
+                // Loopback can't reach a STUN server, and the family must match
+                let Some(addr) = addrs
+                    .iter()
+                    .find(|a| a.is_ipv4() == base.is_ipv4() && !base.ip().is_loopback())
+                else {
+                    continue;
+                };
+
+                let request = stun::Message::new(stun::BINDING_REQUEST);
+                if let Err(e) = socket.send_to(&request.encode(), addr).await {
+                    debug!("stun request {} => {} failed: {:?}", base, addr, e);
+                    continue;
+                }
+                pending.insert(request.transaction_id, (*addr, base));
+            }
+        }
+
+        let mut candidates = vec![];
+        let deadline = Instant::now() + STUN_TIMEOUT;
+        while !pending.is_empty() {
+            let Ok(Ok((n, source, destination))) =
+                tokio::time::timeout_at(deadline.into(), recv_any(&self.sockets, &mut self.buf))
+                    .await
+            else {
+                break;
+            };
+            let response = stun::Message::decode(&self.buf[..n]).filter(|response| {
+                response.method == stun::BINDING_RESPONSE
+                    && pending.contains_key(&response.transaction_id)
+            });
+            let Some(response) = response else {
+                // Media and the peer's checks still arrive while we gather
+                // during a restart or after the answer, str0m needs them
+                self.receive_udp(n, source, destination);
+                continue;
+            };
+            let Some((server, base)) = pending.remove(&response.transaction_id) else {
+                continue;
+            };
+            let Some(mapped) = response.mapped_address() else {
+                continue;
+            };
+
+            info!("stun {} mapped {} to {}", server, base, mapped);
+            // Without a NAT there's nothing to add over the host candidate
+            if mapped == base {
+                continue;
+            }
+            match Candidate::server_reflexive(mapped, base, Protocol::Udp) {
+                Ok(candidate) if !candidates.contains(&candidate) => candidates.push(candidate),
+                Ok(_) => {}
+                Err(e) => warn!("invalid srflx candidate {}: {:?}", mapped, e),
+            }
+        }
+
+        for (server, base) in pending.values() {
+            warn!("no stun response from {} for {}", server, base);
+        }
+        candidates
+    }
+
+    // Hand a datagram read outside of recv() to str0m
+    fn receive_udp(&mut self, n: usize, source: SocketAddr, destination: SocketAddr) {
+        let Ok(contents) = (&self.buf[..n]).try_into() else {
+            return;
+        };
+        let input = Input::Receive(
+            Instant::now(),
+            Receive {
+                proto: Protocol::Udp,
+                source,
+                destination,
+                contents,
+            },
+        );
+        if let Err(e) = self.rtc.handle_input(input) {
+            debug!("error handling input from {}: {:?}", source, e);
+        }
+    }
+
+    // Tear down the session, deleting the WHIP/WHEP resource if we created one
+    pub async fn close(&mut self) {
+        self.rtc.disconnect();
+        if let Some(mut turn) = self.turn.take() {
+            turn.close().await;
+        }
+
+        let (Some(http), Some(url)) = (self.http.as_ref(), self.resource_url.take()) else {
+            return;
+        };
+        info!("deleting resource {}", url);
+        match http.delete(url).send().await {
+            Ok(res) if res.status().is_success() => {}
+            Ok(res) => warn!("DELETE failed with status: {}", res.status()),
+            Err(e) => warn!("DELETE failed: {:?}", e),
+        }
+    }
+
+    // Add candidates gathered after the offer was sent, trickling them to the
+    // WHIP/WHEP resource along with end-of-candidates once gathering is done
+    pub async fn trickle_candidates(
+        &mut self,
+        candidates: Vec<Candidate>,
+        end_of_candidates: bool,
+    ) -> Result<(), WebrtcError> {
+        for candidate in &candidates {
+            self.rtc.add_local_candidate(candidate.clone());
+        }
+
+        let (Some(http), Some(url)) = (self.http.as_ref(), self.resource_url.as_ref()) else {
+            return Ok(());
+        };
+        if !self.trickle_supported {
+            return Ok(());
+        }
+
+        let frag = SdpFragment {
+            candidates: candidates
+                .iter()
+                .map(|c| with_tcptype(&c.to_string()))
+                .collect(),
+            end_of_candidates,
+            ..self.local_frag.clone()
+        };
+        debug!("trickle: {}", frag);
+
+        let mut req = http
+            .patch(url.clone())
+            .header(CONTENT_TYPE, sdpfrag::CONTENT_TYPE)
+            .body(frag.to_string());
+        if let Some(etag) = &self.etag {
+            req = req.header(IF_MATCH, etag);
+        }
+        let res = req
+            .send()
+            .await
+            .map_err(|e| WebrtcError::ServerError(e.into()))?;
+
+        match res.status() {
+            StatusCode::OK | StatusCode::NO_CONTENT => Ok(()),
+            // Trickle is optional for WHIP servers, fall back to the offer's candidates
+            StatusCode::METHOD_NOT_ALLOWED
+            | StatusCode::NOT_IMPLEMENTED
+            | StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+                info!("server does not support trickle ice: {}", res.status());
+                self.trickle_supported = false;
+                Ok(())
+            }
+            status => Err(WebrtcError::ServerError(
+                format!("PATCH failed with status: {}", status).into(),
+            )),
+        }
+    }
+
+    pub fn add_remote_candidate(&mut self, candidate: Candidate) {
+        self.rtc.add_remote_candidate(candidate);
+    }
+
     pub fn accept_whip_request(&mut self, offer: String) -> Result<String, WebrtcError> {
-        let offer = SdpOffer::from_sdp_string(&offer).map_err(|_| WebrtcError::SdpError)?;
-        if let Ok(answer) = self.rtc.sdp_api().accept_offer(offer) {
-            return Ok(answer.to_sdp_string());
+        let parsed = SdpOffer::from_sdp_string(&offer).map_err(|_| WebrtcError::SdpError)?;
+        if let Ok(answer) = self.rtc.sdp_api().accept_offer(parsed) {
+            self.remote_sdp = Some(offer);
+            return Ok(with_tcptype(&answer.to_sdp_string()));
         }
 
         return Err(WebrtcError::SdpError);
     }
 
+    pub fn is_connected(&self) -> bool {
+        matches!(
+            self.ice_state,
+            IceConnectionState::Connected | IceConnectionState::Completed
+        )
+    }
+
+    // The socket bound to a candidate's address, or failing that any of the
+    // destination's family that isn't loopback
+    fn socket_for(&self, source: SocketAddr, destination: SocketAddr) -> Option<&UdpSocket> {
+        let addrs = || {
+            self.sockets
+                .iter()
+                .filter_map(|socket| Some((socket, socket.local_addr().ok()?)))
+        };
+        addrs()
+            .find(|(_, addr)| *addr == source)
+            .or_else(|| {
+                addrs().find(|(_, addr)| {
+                    addr.is_ipv4() == destination.is_ipv4() && !addr.ip().is_loopback()
+                })
+            })
+            .map(|(socket, _)| socket)
+    }
+
+    // Only the side that created the resource can PATCH it with a restart
+    pub fn can_restart_ice(&self) -> bool {
+        self.http.is_some() && self.resource_url.is_some()
+    }
+
+    // Restart ICE after a network change, keeping the same Rtc and media. The
+    // new credentials and regathered candidates go to the resource in an
+    // sdpfrag, the server answers with its own new credentials
+    pub async fn restart_ice(&mut self) -> Result<(), WebrtcError> {
+        let (Some(http), Some(url)) = (self.http.clone(), self.resource_url.clone()) else {
+            return Err(WebrtcError::ServerError(
+                "no resource to restart ice on".into(),
+            ));
+        };
+        let remote_sdp = self.remote_sdp.clone().ok_or(WebrtcError::SdpError)?;
+
+        // Interfaces may have come and gone, keep sockets on addresses we
+        // still have and bind any new ones, then gather again from scratch
+        let ips = interface_ips(&self.network)?;
+        self.sockets.retain(|socket| {
+            socket
+                .local_addr()
+                .is_ok_and(|addr| ips.contains(&addr.ip()))
+        });
+        let bound: Vec<IpAddr> = self
+            .sockets
+            .iter()
+            .filter_map(|socket| socket.local_addr().ok())
+            .map(|addr| addr.ip())
+            .collect();
+        let unbound: Vec<IpAddr> = ips.into_iter().filter(|ip| !bound.contains(ip)).collect();
+        self.sockets.extend(bind_host_sockets(&unbound).await);
+        if self.sockets.is_empty() {
+            return Err(WebrtcError::NoCandidates);
+        }
+
+        let mut candidates: Vec<Candidate> =
+            self.sockets.iter().filter_map(host_candidate).collect();
+        let servers = self.ice_servers.clone();
+        candidates.extend(self.gather_srflx(&servers).await);
+        candidates.extend(self.relay_candidate());
+        candidates.extend(tcp_candidates(&self.tcp));
+
+        let mut change = self.rtc.sdp_api();
+        change.ice_restart(false);
+        let (offer, pending) = change.apply().ok_or(WebrtcError::SdpError)?;
+        self.local_frag = SdpFragment::parse(&offer.to_sdp_string());
+
+        for candidate in &candidates {
+            self.rtc.add_local_candidate(candidate.clone());
+        }
+
+        let frag = SdpFragment {
+            candidates: candidates
+                .iter()
+                .map(|c| with_tcptype(&c.to_string()))
+                .collect(),
+            end_of_candidates: true,
+            ..self.local_frag.clone()
+        };
+        info!("ice restart: {}", frag);
+
+        // Restarts must not be rejected for a stale entity tag
+        let res = http
+            .patch(url)
+            .header(CONTENT_TYPE, sdpfrag::CONTENT_TYPE)
+            .header(IF_MATCH, "*")
+            .body(frag.to_string())
+            .send()
+            .await
+            .map_err(|e| WebrtcError::ServerError(e.into()))?;
+
+        if res.status() != StatusCode::OK {
+            return Err(WebrtcError::ServerError(
+                format!("ICE restart failed with status: {}", res.status()).into(),
+            ));
+        }
+        self.etag = res
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|e| e.to_str().ok())
+            .map(str::to_string);
+        let body = res
+            .text()
+            .await
+            .map_err(|e| WebrtcError::ServerError(e.into()))?;
+
+        // Feed the remote's new credentials and candidates through str0m as an answer
+        let answer = SdpFragment::parse(&body).apply_to(&remote_sdp);
+        self.rtc
+            .sdp_api()
+            .accept_answer(
+                pending,
+                SdpAnswer::from_sdp_string(&answer).map_err(|_| WebrtcError::SdpError)?,
+            )
+            .map_err(|_| WebrtcError::SdpError)?;
+        self.remote_sdp = Some(answer);
+        Ok(())
+    }
+
+    // Server side of an ICE restart, returns our new credentials and candidates
+    pub fn accept_ice_restart(&mut self, frag: &SdpFragment) -> Result<SdpFragment, WebrtcError> {
+        let remote_sdp = self.remote_sdp.as_deref().ok_or(WebrtcError::SdpError)?;
+        let offer = frag.apply_to(remote_sdp);
+        let answer = self
+            .rtc
+            .sdp_api()
+            .accept_offer(SdpOffer::from_sdp_string(&offer).map_err(|_| WebrtcError::SdpError)?)
+            .map_err(|_| WebrtcError::SdpError)?;
+        self.remote_sdp = Some(offer);
+
+        let mut local = SdpFragment::parse(&with_tcptype(&answer.to_sdp_string()));
+        local.end_of_candidates = true;
+        Ok(local)
+    }
+
     pub async fn recv<'a>(&mut self) -> Result<WebrtcEvent, WebrtcError> {
         trace!("recv poll_output()");
         let timeout = match self
@@ -226,22 +845,23 @@ impl Client {
                 }
                 Event::IceConnectionStateChange(state) => {
                     info!("ice connection state change: {:?}", state);
+                    self.ice_state = state;
                     match state {
                         IceConnectionState::Disconnected => return Ok(WebrtcEvent::Disconnected),
                         _ => return Ok(WebrtcEvent::Continue),
                     }
                 }
                 Event::MediaIngressStats(stats) => {
-                    info!("egress stats: {:?}", stats);
-                    return Ok(WebrtcEvent::Continue);
+                    debug!("ingress stats: {:?}", stats);
+                    return Ok(WebrtcEvent::IngressStats(stats));
                 }
                 Event::MediaEgressStats(stats) => {
                     info!("egress stats: {:?}", stats);
                     return Ok(WebrtcEvent::Continue);
                 }
                 Event::PeerStats(stats) => {
-                    info!("stats: {:?}", stats);
-                    return Ok(WebrtcEvent::Continue);
+                    debug!("stats: {:?}", stats);
+                    return Ok(WebrtcEvent::PeerStats(stats));
                 }
                 Event::MediaData(media) => {
                     return Ok(WebrtcEvent::Media(media));
@@ -257,8 +877,23 @@ impl Client {
             },
             Output::Timeout(timeout) => timeout,
             Output::Transmit(send) => {
-                // Apply random packet loss to outbound traffic
-                if let Err(e) = self.socket.send_to(&send.contents, send.destination).await {
+                let result = match (&mut self.turn, &self.tcp) {
+                    (_, Some(tcp)) if send.proto == Protocol::Tcp => {
+                        tcp.send(send.source, send.destination, &send.contents);
+                        Ok(())
+                    }
+                    (Some(turn), _) if send.source == turn.relayed_addr() => {
+                        turn.send_to(&send.contents, send.destination).await
+                    }
+                    _ => match self.socket_for(send.source, send.destination) {
+                        Some(socket) => socket
+                            .send_to(&send.contents, send.destination)
+                            .await
+                            .map(|_| ()),
+                        None => Err(ErrorKind::AddrNotAvailable.into()),
+                    },
+                };
+                if let Err(e) = result {
                     debug!(
                         "sending to {} => {}, len {} error {:?}",
                         send.source,
@@ -283,29 +918,94 @@ impl Client {
             };
         }
 
-        let input = match tokio::time::timeout(duration, self.socket.recv_from(&mut self.buf)).await
-        {
-            Ok(Ok((n, source))) => {
-                // UDP data received.
-                info!(
-                    "received from {} => {}, len {}",
-                    source,
-                    SocketAddr::new(
-                        self.local_socket_addr.ip(),
-                        self.socket.local_addr().unwrap().port(),
-                    ),
-                    n
+        if let Some(turn) = &mut self.turn {
+            if let Err(e) = turn.maintain().await {
+                warn!("turn refresh failed: {:?}", e);
+            }
+        }
+
+        let Self {
+            sockets,
+            buf,
+            turn,
+            tcp,
+            ..
+        } = self;
+        let received = tokio::time::timeout(duration, async {
+            tokio::select! {
+                received = recv_any(sockets, buf) => {
+                    received.map(|(n, source, destination)| Received::Socket(n, source, destination))
+                }
+                received = recv_relay(turn) => received,
+                packet = recv_tcp(tcp) => Ok(Received::Tcp(packet)),
+            }
+        })
+        .await;
+
+        let input = match received {
+            Ok(Ok(Received::Relay(peer, data))) => {
+                let Ok(contents) = data.as_slice().try_into() else {
+                    debug!("dropping relayed packet from {}, len {}", peer, data.len());
+                    return Ok(WebrtcEvent::Continue);
+                };
+                let input = Input::Receive(
+                    Instant::now(),
+                    Receive {
+                        proto: Protocol::Udp,
+                        source: peer,
+                        destination: self.turn.as_ref().unwrap().relayed_addr(),
+                        contents,
+                    },
+                );
+                self.rtc
+                    .handle_input(input)
+                    .map_err(|e| WebrtcError::WebrtcError(e.into()))?;
+                return Ok(WebrtcEvent::Continue);
+            }
+            Ok(Ok(Received::Tcp(packet))) => {
+                trace!(
+                    "received tcp from {} => {}, len {}",
+                    packet.source,
+                    packet.destination,
+                    packet.contents.len()
+                );
+                let Ok(contents) = packet.contents.as_slice().try_into() else {
+                    debug!(
+                        "dropping tcp packet from {}, len {}",
+                        packet.source,
+                        packet.contents.len()
+                    );
+                    return Ok(WebrtcEvent::Continue);
+                };
+                let input = Input::Receive(
+                    Instant::now(),
+                    Receive {
+                        proto: Protocol::Tcp,
+                        source: packet.source,
+                        destination: packet.destination,
+                        contents,
+                    },
                 );
+                self.rtc
+                    .handle_input(input)
+                    .map_err(|e| WebrtcError::WebrtcError(e.into()))?;
+                return Ok(WebrtcEvent::Continue);
+            }
+            Ok(Ok(Received::Control)) => return Ok(WebrtcEvent::Continue),
+            Ok(Ok(Received::Socket(n, source, destination))) => {
+                // UDP data received.
+                info!("received from {} => {}, len {}", source, destination, n);
+                let Ok(contents) = (&self.buf[..n]).try_into() else {
+                    debug!("dropping packet from {}, len {}", source, n);
+                    return Ok(WebrtcEvent::Continue);
+                };
                 Input::Receive(
                     Instant::now(),
                     Receive {
                         proto: Protocol::Udp,
                         source,
-                        destination: SocketAddr::new(
-                            self.local_socket_addr.ip(),
-                            self.socket.local_addr().unwrap().port(),
-                        ),
-                        contents: (&self.buf[..n]).try_into().expect("should webrtc"),
+                        destination,
+                        contents,
                     },
                 )
             }
@@ -360,4 +1060,110 @@ impl Client {
         }
         Ok(())
     }
+
+    pub fn send_audio(&mut self, data: Bytes, pts: Duration) -> Result<(), WebrtcError> {
+        let Some(mid) = self.audio_mid else {
+            warn!("trying to send audio without mid");
+            return Ok(());
+        };
+        let Some(params) = self
+            .rtc
+            .codec_config()
+            .find(|p| p.spec().codec == Codec::Opus)
+            .cloned()
+        else {
+            return Err(WebrtcError::SendError("opus not negotiated".to_string()));
+        };
+        if let Some(writer) = self.rtc.writer(mid) {
+            // Same media clock as the video, rebased to the opus rate
+            let media_time: MediaTime = pts.into();
+            writer
+                .write(
+                    params.pt(),
+                    Instant::now(),
+                    media_time.rebase(params.spec().clock_rate),
+                    data,
+                )
+                .map_err(|e| WebrtcError::SendError(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(ice_servers: Vec<String>) -> NetworkConfig {
+        NetworkConfig {
+            ice_servers,
+            turn_username: None,
+            turn_credential: None,
+            interfaces: vec![],
+            exclude_interfaces: vec![],
+            // Loopback sockets never send binding requests
+            exclude_loopback: true,
+            include_link_local: false,
+            no_ipv6: true,
+            no_ice_tcp: true,
+        }
+    }
+
+    // A STUN server answering every binding request as if the client were
+    // behind a NAT mapping it to `mapped`
+    async fn stun_stand_in(ip: IpAddr, mapped: SocketAddr) -> SocketAddr {
+        let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            while let Ok((n, source)) = socket.recv_from(&mut buf).await {
+                let Some(request) = stun::Message::decode(&buf[..n]) else {
+                    continue;
+                };
+                if request.method != stun::BINDING_REQUEST {
+                    continue;
+                }
+                let response = stun::Message {
+                    method: stun::BINDING_RESPONSE,
+                    transaction_id: request.transaction_id,
+                    attributes: vec![],
+                }
+                .with(
+                    stun::ATTR_XOR_MAPPED_ADDRESS,
+                    stun::encode_address(mapped, &request.transaction_id),
+                );
+                let _ = socket.send_to(&response.encode(), source).await;
+            }
+        });
+        addr
+    }
+
+    // Host candidates skip loopback, so this needs a real interface; run by
+    // the capture workflow with --include-ignored
+    #[tokio::test]
+    #[ignore = "needs a non-loopback IPv4 address"]
+    async fn srflx_candidate_in_offer() {
+        let ip = interface_ips(&network(vec![]))
+            .unwrap()
+            .into_iter()
+            .find(|ip| !ip.is_loopback())
+            .expect("no non-loopback IPv4 address to gather on");
+        let mapped: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let server = stun_stand_in(ip, mapped).await;
+
+        let mut client = Client::new(&network(vec![format!("stun:{}", server)]))
+            .await
+            .unwrap();
+        client.gather_configured().await;
+
+        let mut change = client.rtc.sdp_api();
+        change.add_media(MediaKind::Video, RtcDirection::RecvOnly, None, None);
+        let (offer, _) = change.apply().unwrap();
+        let offer = offer.to_sdp_string();
+        assert!(
+            offer.contains(" 203.0.113.7 40000 typ srflx "),
+            "no srflx candidate in offer:\n{}",
+            offer
+        );
+    }
 }
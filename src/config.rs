@@ -0,0 +1,189 @@
+use crate::{CaptureMethod, StreamArgs};
+
+use anyhow::{anyhow, Context, Result};
+use clap::{parser::ValueSource, ArgMatches, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+
+const FILE_NAME: &str = "bitwhip.toml";
+const DEFAULT_PROFILE: &str = "default";
+
+// bitwhip.toml, a set of named stream profiles:
+//
+// [profiles.gaming]
+// url = "https://example.com/whip"
+// capture_method = "x11"
+// framerate = 60
+// encoder = "libx264"
+// bitrate = 8000
+//
+// [profiles.gaming.encoder_options]
+// tune = "zerolatency"
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub url: Option<String>,
+    pub token: Option<String>,
+    pub capture_method: Option<String>,
+    pub framerate: Option<i32>,
+    pub device: Option<String>,
+    pub size: Option<String>,
+    pub pixel_format: Option<String>,
+    #[serde(rename = "loop")]
+    pub looping: Option<bool>,
+    pub filter: Option<String>,
+    pub encoder: Option<String>,
+    pub bitrate: Option<u32>,
+    pub max_bitrate: Option<u32>,
+    pub gop_seconds: Option<f32>,
+    pub preset: Option<String>,
+    pub encoder_options: Option<BTreeMap<String, String>>,
+}
+
+impl ConfigFile {
+    // An explicit path must exist, otherwise look in the working directory
+    // then the user config directory, and fall back to no profiles at all
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None => match Self::search_paths().into_iter().find(|p| p.is_file()) {
+                Some(path) => path,
+                None => return Ok(Self::default()),
+            },
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    fn search_paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(FILE_NAME)];
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+        if let Some(dir) = config_dir {
+            paths.push(dir.join("bitwhip").join(FILE_NAME));
+        }
+        paths
+    }
+
+    // A named profile must exist, an unnamed lookup uses [profiles.default] if present
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        match name {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .cloned()
+                .ok_or(anyhow!("no profile named {name} in config")),
+            None => Ok(self
+                .profiles
+                .get(DEFAULT_PROFILE)
+                .cloned()
+                .unwrap_or_default()),
+        }
+    }
+}
+
+impl Profile {
+    // Fill in any stream arguments not given explicitly on the command line
+    pub fn apply(&self, args: &mut StreamArgs, matches: &ArgMatches) -> Result<()> {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if !from_cli("url") && self.url.is_some() {
+            args.url = self.url.clone();
+        }
+        if !from_cli("token") && self.token.is_some() {
+            args.token = self.token.clone();
+        }
+        if let Some(method) = self
+            .capture_method
+            .as_deref()
+            .filter(|_| !from_cli("capture_method"))
+        {
+            args.capture_method = CaptureMethod::from_str(method, true)
+                .map_err(|e| anyhow!("invalid capture_method in profile: {e}"))?;
+        }
+
+        let config = &mut args.config;
+        if let Some(framerate) = self.framerate.filter(|_| !from_cli("framerate")) {
+            config.framerate = framerate;
+        }
+        if !from_cli("device") && self.device.is_some() {
+            config.device = self.device.clone();
+        }
+        if !from_cli("size") && self.size.is_some() {
+            config.size = self.size.clone();
+        }
+        if !from_cli("pixel_format") && self.pixel_format.is_some() {
+            config.pixel_format = self.pixel_format.clone();
+        }
+        if let Some(looping) = self.looping.filter(|_| !from_cli("looping")) {
+            config.looping = looping;
+        }
+        if !from_cli("filter") && self.filter.is_some() {
+            config.filter = self.filter.clone();
+        }
+
+        let encoder = &mut args.encoder_config;
+        if !from_cli("encoder") && self.encoder.is_some() {
+            encoder.encoder = self.encoder.clone();
+        }
+        if !from_cli("bitrate") && self.bitrate.is_some() {
+            encoder.bitrate = self.bitrate;
+        }
+        if !from_cli("max_bitrate") && self.max_bitrate.is_some() {
+            encoder.max_bitrate = self.max_bitrate;
+        }
+        if let Some(gop_seconds) = self.gop_seconds.filter(|_| !from_cli("gop_seconds")) {
+            encoder.gop_seconds = gop_seconds;
+        }
+        if !from_cli("preset") && self.preset.is_some() {
+            encoder.preset = self.preset.clone();
+        }
+        // Options merge per key, the later cli values win
+        if let Some(options) = &self.encoder_options {
+            let cli_options = std::mem::take(&mut encoder.options);
+            encoder.options = options.clone().into_iter().chain(cli_options).collect();
+        }
+
+        Ok(())
+    }
+}
+
+impl From<&StreamArgs> for Profile {
+    fn from(args: &StreamArgs) -> Self {
+        let encoder = &args.encoder_config;
+        Self {
+            url: args.url.clone(),
+            // Don't echo secrets back to the terminal
+            token: args.token.as_ref().map(|_| "<redacted>".to_string()),
+            capture_method: args
+                .capture_method
+                .to_possible_value()
+                .map(|v| v.get_name().to_string()),
+            framerate: Some(args.config.framerate),
+            device: args.config.device.clone(),
+            size: args.config.size.clone(),
+            pixel_format: args.config.pixel_format.clone(),
+            looping: Some(args.config.looping),
+            filter: args.config.filter.clone(),
+            encoder: encoder.encoder.clone(),
+            bitrate: encoder.bitrate,
+            max_bitrate: encoder.max_bitrate,
+            gop_seconds: Some(encoder.gop_seconds),
+            preset: encoder.preset.clone(),
+            encoder_options: Some(encoder.options.iter().cloned().collect()),
+        }
+    }
+}
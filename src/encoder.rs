@@ -1,8 +1,13 @@
 use anyhow::{anyhow, bail, Context, Result};
 use ffmpeg::ffi::AVCodecContext;
 use ffmpeg::{
-    codec::Context as CodecContext, encoder::video::Encoder as VideoEncoderOpened,
-    encoder::video::Video as VideoEncoder, frame, Error, Packet,
+    codec::Context as CodecContext,
+    encoder::audio::Encoder as AudioEncoderOpened,
+    encoder::video::Encoder as VideoEncoderOpened,
+    encoder::video::Video as VideoEncoder,
+    format, frame,
+    software::scaling::{Context as Scaler, Flags},
+    Error, Packet,
 };
 use ffmpeg_next::{self as ffmpeg};
 use ffmpeg_sys_next::{av_buffer_ref, AVBufferRef, EAGAIN};
@@ -14,7 +19,7 @@ use std::{
     ffi::{c_void, CString},
 };
 
-use crate::source::{self, Output, PollSource, Source};
+use crate::source::{self, AudioSource, Output, PollSource, Source, SAMPLE_RATE};
 
 pub struct EncodedPacket(pub Packet, pub source::Delta);
 
@@ -28,7 +33,17 @@ impl Default for Codec {
         #[cfg(target_os = "windows")]
         return Self("h264_nvenc".to_string());
         #[cfg(target_os = "linux")]
-        return todo!();
+        return ["libx264", "libopenh264"]
+            .into_iter()
+            .find(|name| ffmpeg::encoder::find_by_name(name).is_some())
+            .map(|name| Self(name.to_string()))
+            .unwrap_or(Self("libx264".to_string()));
+    }
+}
+
+impl From<String> for Codec {
+    fn from(name: String) -> Self {
+        Self(name)
     }
 }
 
@@ -45,6 +60,20 @@ impl Codec {
                 ("preset".into(), "p6".into()),
                 ("tune".into(), "ull".into()),
             ]),
+            // Software encoders: constrained baseline to match the negotiated
+            // 42e01f profile, annex-b with in-band SPS/PPS, no B-frames
+            "libx264" => HashMap::from([
+                ("preset".into(), "veryfast".into()),
+                ("tune".into(), "zerolatency".into()),
+                ("profile".into(), "baseline".into()),
+                ("bf".into(), "0".into()),
+                ("x264-params".into(), "annexb=1:repeat-headers=1".into()),
+            ]),
+            "libopenh264" => HashMap::from([
+                ("profile".into(), "constrained_baseline".into()),
+                ("allow_skip_frames".into(), "1".into()),
+                ("bf".into(), "0".into()),
+            ]),
             _ => HashMap::from([]),
         }
     }
@@ -66,6 +95,7 @@ pub struct EncoderBuilder {
     hw_ctx: Option<*mut AVBufferRef>,
     example_frame: frame::Video,
     customise: Option<Box<dyn FnOnce(&mut VideoEncoder)>>,
+    options: HashMap<String, String>,
 }
 
 impl EncoderBuilder {
@@ -75,6 +105,7 @@ impl EncoderBuilder {
             hw_ctx: None,
             example_frame: frame::Video::empty(),
             customise: None,
+            options: HashMap::new(),
         }
     }
 
@@ -91,13 +122,20 @@ impl EncoderBuilder {
         self
     }
 
+    // AVOptions applied over the codec defaults
+    pub fn set_options(mut self, options: HashMap<String, String>) -> Self {
+        self.options.extend(options);
+        self
+    }
+
     pub fn customise(mut self, f: impl FnOnce(&mut VideoEncoder) + 'static) -> Self {
         self.customise = Some(Box::new(f));
         self
     }
 
     pub fn open(mut self) -> Result<VideoEncoderOpened> {
-        let settings = self.codec.default_settings();
+        let mut settings = self.codec.default_settings();
+        settings.extend(self.options.drain());
         let name: String = self.codec.into();
 
         let codec = ffmpeg::encoder::find_by_name(name.as_str())
@@ -124,14 +162,16 @@ impl EncoderBuilder {
             }
         }
 
-        // set any encoder specific options from defaults
-        // in future, we could merge cli args in as well
+        // set any encoder specific options, cli args take precedence over defaults
         for (key, value) in settings.iter() {
             info!("Setting option {key} {value}");
-            unsafe { Self::set_option(enc.as_mut_ptr(), key, value)? };
+            unsafe { Self::set_option(enc.as_mut_ptr(), key, value) }
+                .with_context(|| format!("Invalid option for encoder {name}"))?;
         }
 
-        Ok(enc.open()?)
+        // some options, eg. an unknown preset, are only rejected on open
+        enc.open()
+            .with_context(|| format!("Failed to open encoder {name}"))
     }
 
     // helpers
@@ -146,7 +186,7 @@ impl EncoderBuilder {
             ffmpeg::ffi::AV_OPT_SEARCH_CHILDREN,
         );
         if retval != 0 {
-            bail!("set_option failed: {retval}");
+            bail!("{name}={val}: {}", Error::from(retval));
         }
         Ok(())
     }
@@ -158,16 +198,19 @@ pub struct EncodedPacketIter<T> {
     encoder: VideoEncoderOpened,
     frame_next: frame::Video,
     frame_timestamp: Duration,
+    flushing: bool,
 }
 
 impl<T> EncodedPacketIter<T> {
-    pub fn new(encoder: VideoEncoderOpened, source: T) -> Self {
+    // start is the clock packets are timestamped against, shared with audio
+    pub fn new(encoder: VideoEncoderOpened, source: T, start: Instant) -> Self {
         let target_fps = encoder.frame_rate();
         Self {
             encoder,
-            source: PollSource::new(source, target_fps, Instant::now()),
+            source: PollSource::new(source, target_fps, start),
             frame_next: frame::Video::empty(),
             frame_timestamp: Duration::new(0, 0),
+            flushing: false,
         }
     }
 }
@@ -186,16 +229,29 @@ where
                 // Reuse timestamp for all frame packets
                 Ok(_) => return Some(Ok(EncodedPacket(p, self.frame_timestamp))),
                 Err(Error::Other { errno }) if errno == EAGAIN => {}
+                // Fully drained after source completion
+                Err(Error::Eof) => return None,
                 Err(e) => return Some(Err(e.into())),
             }
 
+            if self.flushing {
+                return None;
+            }
+
             // get next frame since encoder is empty
             loop {
                 let now = Instant::now();
                 match self.source.next(now, &mut self.frame_next) {
-                    Output::Complete => return None,
+                    Output::Complete => {
+                        // Flush delayed packets out of the encoder before finishing
+                        if let Err(e) = self.encoder.send_eof() {
+                            return Some(Err(e.into()));
+                        }
+                        self.flushing = true;
+                        break;
+                    }
                     Output::Item(Ok(_), time_delta) => {
-                        // We will use simple sys time delta for rtp timestamp
+                        // Sys time delta, or the source media time, used for rtp timestamp
                         self.frame_timestamp = time_delta;
                         break;
                     }
@@ -207,6 +263,148 @@ where
                 };
             }
 
+            if self.flushing {
+                continue;
+            }
+
+            if let Err(e) = self.encoder.send_frame(&self.frame_next) {
+                return Some(Err(e.into()));
+            }
+        }
+    }
+}
+
+// Opus at 48kHz stereo, fed s16 frames from an AudioSource
+pub fn open_opus(bitrate_kbps: u32) -> Result<AudioEncoderOpened> {
+    let codec = ffmpeg::encoder::find_by_name("libopus")
+        .ok_or_else(|| anyhow!("Missing encoder libopus"))?;
+    let mut enc = CodecContext::new_with_codec(codec).encoder().audio()?;
+    enc.set_rate(SAMPLE_RATE as i32);
+    enc.set_format(format::Sample::I16(format::sample::Type::Packed));
+    enc.set_time_base((1, SAMPLE_RATE as i32));
+    enc.set_bit_rate(bitrate_kbps as usize * 1000);
+
+    let settings = [("ch_layout", "stereo"), ("application", "lowdelay")];
+    for (key, value) in settings {
+        unsafe { EncoderBuilder::set_option(enc.as_mut_ptr(), key, value) }
+            .context("Invalid option for encoder libopus")?;
+    }
+
+    enc.open_as(codec).context("Failed to open encoder libopus")
+}
+
+// Encodes a single frame as a still image with an image codec, eg. PNG,
+// converting it to the pixel format the encoder takes first
+pub fn encode_image(
+    input: &frame::Video,
+    id: ffmpeg::codec::Id,
+    pixel: format::Pixel,
+) -> Result<Vec<u8>> {
+    let (width, height) = (input.width(), input.height());
+
+    let mut image = frame::Video::new(pixel, width, height);
+    Scaler::get(
+        input.format(),
+        width,
+        height,
+        pixel,
+        width,
+        height,
+        Flags::BILINEAR,
+    )?
+    .run(input, &mut image)?;
+    image.set_pts(Some(0));
+
+    let codec = ffmpeg::encoder::find(id).ok_or_else(|| anyhow!("Missing encoder {:?}", id))?;
+    let mut enc = CodecContext::new_with_codec(codec).encoder().video()?;
+    enc.set_width(width);
+    enc.set_height(height);
+    enc.set_format(pixel);
+    enc.set_time_base((1, 1));
+    let mut enc = enc
+        .open_as(codec)
+        .with_context(|| format!("Failed to open encoder {:?}", id))?;
+
+    enc.send_frame(&image)?;
+    enc.send_eof()?;
+    let mut packet = Packet::empty();
+    enc.receive_packet(&mut packet)?;
+    Ok(packet.data().unwrap_or_default().to_vec())
+}
+
+// Encoded audio packets, timestamped on the same clock as the video
+pub struct EncodedAudioIter<T> {
+    source: T,
+    encoder: AudioEncoderOpened,
+    frame_next: frame::Audio,
+    start: Instant,
+    // Clock time of the first sample, packet pts count samples from there
+    first_sample: Option<Duration>,
+    samples: i64,
+    flushing: bool,
+}
+
+impl<T> EncodedAudioIter<T> {
+    pub fn new(encoder: AudioEncoderOpened, source: T, start: Instant) -> Self {
+        Self {
+            source,
+            encoder,
+            frame_next: frame::Audio::empty(),
+            start,
+            first_sample: None,
+            samples: 0,
+            flushing: false,
+        }
+    }
+}
+
+impl<T> Iterator for EncodedAudioIter<T>
+where
+    T: AudioSource,
+{
+    type Item = Result<EncodedPacket>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut p = Packet::empty();
+        loop {
+            match self.encoder.receive_packet(&mut p) {
+                Ok(_) => {
+                    // Encoder priming gives the first packets negative pts
+                    let samples = p.pts().unwrap_or(0).max(0) as f64;
+                    let pts = self.first_sample.unwrap_or_default()
+                        + Duration::from_secs_f64(samples / SAMPLE_RATE as f64);
+                    return Some(Ok(EncodedPacket(p, pts)));
+                }
+                Err(Error::Other { errno }) if errno == EAGAIN => {}
+                Err(Error::Eof) => return None,
+                Err(e) => return Some(Err(e.into())),
+            }
+
+            if self.flushing {
+                return None;
+            }
+
+            match self.source.next_frame(&mut self.frame_next) {
+                Ok(_) => {}
+                Err(Error::Eof) => {
+                    if let Err(e) = self.encoder.send_eof() {
+                        return Some(Err(e.into()));
+                    }
+                    self.flushing = true;
+                    continue;
+                }
+                Err(Error::Other { errno }) if errno == EAGAIN => {
+                    sleep(Duration::from_millis(5));
+                    continue;
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+
+            self.first_sample
+                .get_or_insert_with(|| self.start.elapsed());
+            self.frame_next.set_pts(Some(self.samples));
+            self.samples += self.frame_next.samples() as i64;
+
             if let Err(e) = self.encoder.send_frame(&self.frame_next) {
                 return Some(Err(e.into()));
             }
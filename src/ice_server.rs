@@ -0,0 +1,215 @@
+use reqwest::header::{HeaderMap, LINK};
+use tracing::warn;
+
+// An ICE server advertised by a WHIP/WHEP endpoint, eg.
+// Link: <turn:turn.example.net?transport=udp>; rel="ice-server";
+//       username="user"; credential="secret"; credential-type="password"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IceServer {
+    pub url: String,
+    pub username: Option<String>,
+    pub credential: Option<String>,
+}
+
+impl IceServer {
+    pub fn is_stun(&self) -> bool {
+        self.url.starts_with("stun:")
+    }
+
+    pub fn is_turn(&self) -> bool {
+        self.url.starts_with("turn:") || self.url.starts_with("turns:")
+    }
+
+    // host:port for stun: and turn: uris, without any ?transport= query
+    pub fn host_port(&self) -> Option<String> {
+        let (scheme, rest) = self.url.split_once(':')?;
+        let host = rest.split('?').next()?;
+        if host.contains(':') && !host.ends_with(']') {
+            return Some(host.to_string());
+        }
+        let port = match scheme {
+            "stuns" | "turns" => 5349,
+            _ => 3478,
+        };
+        Some(format!("{host}:{port}"))
+    }
+}
+
+// All rel="ice-server" links, a header may carry several comma separated links
+pub fn from_headers(headers: &HeaderMap) -> Vec<IceServer> {
+    headers
+        .get_all(LINK)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(split_links)
+        .filter_map(parse_link)
+        .collect()
+}
+
+fn split_links(header: &str) -> Vec<&str> {
+    // Commas may appear inside quoted parameters
+    let mut links = vec![];
+    let (mut start, mut quoted) = (0, false);
+    for (i, c) in header.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                links.push(&header[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    links.push(&header[start..]);
+    links
+}
+
+fn parse_link(link: &str) -> Option<IceServer> {
+    let mut parts = link.split(';').map(str::trim);
+    let url = parts.next()?.strip_prefix('<')?.strip_suffix('>')?;
+
+    let mut server = IceServer {
+        url: url.to_string(),
+        username: None,
+        credential: None,
+    };
+    let mut ice_server = false;
+    for param in parts {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"').to_string();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "rel" => ice_server = value == "ice-server",
+            "username" => server.username = Some(value),
+            "credential" => server.credential = Some(value),
+            "credential-type" if value != "password" => {
+                warn!("unsupported credential-type {} for {}", value, url);
+            }
+            _ => {}
+        }
+    }
+
+    ice_server.then_some(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn server(url: &str, username: Option<&str>, credential: Option<&str>) -> IceServer {
+        IceServer {
+            url: url.to_string(),
+            username: username.map(str::to_string),
+            credential: credential.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn several_links_in_one_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LINK,
+            HeaderValue::from_static(
+                "<stun:stun.example.net>; rel=\"ice-server\", \
+                 <turn:turn.example.net?transport=udp>; rel=\"ice-server\"; \
+                 username=\"user\"; credential=\"secret\"; credential-type=\"password\"",
+            ),
+        );
+        assert_eq!(
+            from_headers(&headers),
+            [
+                server("stun:stun.example.net", None, None),
+                server(
+                    "turn:turn.example.net?transport=udp",
+                    Some("user"),
+                    Some("secret")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn several_link_headers() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            LINK,
+            HeaderValue::from_static("<stun:one.example.net:3479>; rel=\"ice-server\""),
+        );
+        headers.append(
+            LINK,
+            HeaderValue::from_static(
+                "<turns:two.example.net>; rel=ice-server; username=u; credential=c",
+            ),
+        );
+        assert_eq!(
+            from_headers(&headers),
+            [
+                server("stun:one.example.net:3479", None, None),
+                server("turns:two.example.net", Some("u"), Some("c")),
+            ]
+        );
+    }
+
+    #[test]
+    fn quoted_comma() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LINK,
+            HeaderValue::from_static(
+                "<turn:turn.example.net>; rel=\"ice-server\"; username=\"a,b\"; \
+                 credential=\"c,d\", <stun:stun.example.net>; rel=\"ice-server\"",
+            ),
+        );
+        assert_eq!(
+            from_headers(&headers),
+            [
+                server("turn:turn.example.net", Some("a,b"), Some("c,d")),
+                server("stun:stun.example.net", None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_links_ignored() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LINK,
+            HeaderValue::from_static(
+                "<https://example.net/docs>; rel=\"help\", stun:no-brackets; rel=\"ice-server\", \
+                 <stun:stun.example.net>",
+            ),
+        );
+        assert!(from_headers(&headers).is_empty());
+    }
+
+    #[test]
+    fn host_port() {
+        let host_port = |url| server(url, None, None).host_port();
+        assert_eq!(
+            host_port("stun:stun.example.net"),
+            Some("stun.example.net:3478".into())
+        );
+        assert_eq!(
+            host_port("stun:192.0.2.1:19302"),
+            Some("192.0.2.1:19302".into())
+        );
+        assert_eq!(
+            host_port("turn:turn.example.net:80?transport=tcp"),
+            Some("turn.example.net:80".into())
+        );
+        assert_eq!(
+            host_port("turns:turn.example.net"),
+            Some("turn.example.net:5349".into())
+        );
+        assert_eq!(
+            host_port("stun:[2001:db8::1]"),
+            Some("[2001:db8::1]:3478".into())
+        );
+        assert_eq!(
+            host_port("stun:[2001:db8::1]:3479"),
+            Some("[2001:db8::1]:3479".into())
+        );
+    }
+}
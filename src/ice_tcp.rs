@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use tracing::{debug, info, warn};
+
+// A packet read off an ICE-TCP connection
+pub struct TcpPacket {
+    pub source: SocketAddr,
+    // The local candidate address the connection belongs to
+    pub destination: SocketAddr,
+    pub contents: Vec<u8>,
+}
+
+type Connections = Arc<Mutex<HashMap<SocketAddr, UnboundedSender<Vec<u8>>>>>;
+
+// ICE-TCP (RFC 6544) transport with passive candidates only, accepting
+// connections on a listener per interface. Packets are framed with a 2 byte
+// length, RFC 4571
+pub struct IceTcp {
+    listeners: Vec<SocketAddr>,
+    accept_tasks: Vec<JoinHandle<()>>,
+    // Writer for each connected peer
+    connections: Connections,
+    packets_tx: UnboundedSender<TcpPacket>,
+    packets_rx: UnboundedReceiver<TcpPacket>,
+}
+
+impl IceTcp {
+    pub async fn bind(ips: &[IpAddr]) -> Self {
+        let (packets_tx, packets_rx) = mpsc::unbounded_channel();
+        let connections = Connections::default();
+
+        let (mut listeners, mut accept_tasks) = (vec![], vec![]);
+        for ip in ips {
+            let listener = match TcpListener::bind(SocketAddr::new(*ip, 0)).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    warn!("failed to bind tcp listener on {}: {:?}", ip, e);
+                    continue;
+                }
+            };
+            let Ok(local) = listener.local_addr() else {
+                continue;
+            };
+            info!("ice-tcp listening on {}", local);
+            listeners.push(local);
+
+            let (connections, packets_tx) = (connections.clone(), packets_tx.clone());
+            accept_tasks.push(tokio::task::spawn(async move {
+                while let Ok((stream, peer)) = listener.accept().await {
+                    info!("ice-tcp connection from {} on {}", peer, local);
+                    start_connection(stream, peer, local, &connections, &packets_tx);
+                }
+            }));
+        }
+
+        Self {
+            listeners,
+            accept_tasks,
+            connections,
+            packets_tx,
+            packets_rx,
+        }
+    }
+
+    // Addresses of the passive candidates
+    pub fn listeners(&self) -> &[SocketAddr] {
+        &self.listeners
+    }
+
+    pub fn send(&self, source: SocketAddr, destination: SocketAddr, contents: &[u8]) {
+        let connections = self.connections.lock().unwrap();
+        let Some(writer) = connections.get(&destination) else {
+            // We only listen, the peer has to connect first
+            debug!(
+                "no ice-tcp connection from {} to {}, waiting for the peer",
+                source, destination
+            );
+            return;
+        };
+        let _ = writer.send(contents.to_vec());
+    }
+
+    pub async fn recv(&mut self) -> TcpPacket {
+        // We hold a sender ourselves, the channel never closes
+        self.packets_rx.recv().await.unwrap()
+    }
+}
+
+impl Drop for IceTcp {
+    fn drop(&mut self) {
+        for task in &self.accept_tasks {
+            task.abort();
+        }
+        // Writers end once their senders are gone, taking the connections down
+        self.connections.lock().unwrap().clear();
+    }
+}
+
+fn start_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    local: SocketAddr,
+    connections: &Connections,
+    packets_tx: &UnboundedSender<TcpPacket>,
+) {
+    let (writer, pending) = mpsc::unbounded_channel();
+    connections.lock().unwrap().insert(peer, writer);
+    spawn_reader(
+        stream,
+        peer,
+        local,
+        connections.clone(),
+        packets_tx.clone(),
+        pending,
+    );
+}
+
+fn spawn_reader(
+    stream: TcpStream,
+    peer: SocketAddr,
+    local: SocketAddr,
+    connections: Connections,
+    packets_tx: UnboundedSender<TcpPacket>,
+    pending: UnboundedReceiver<Vec<u8>>,
+) {
+    let _ = stream.set_nodelay(true);
+    let (read, write) = stream.into_split();
+    tokio::task::spawn(write_loop(write, pending));
+    tokio::task::spawn(async move {
+        if let Err(e) = read_loop(read, peer, local, packets_tx).await {
+            debug!("ice-tcp connection to {} closed: {:?}", peer, e);
+        }
+        connections.lock().unwrap().remove(&peer);
+    });
+}
+
+async fn read_loop(
+    mut read: OwnedReadHalf,
+    source: SocketAddr,
+    destination: SocketAddr,
+    packets_tx: UnboundedSender<TcpPacket>,
+) -> std::io::Result<()> {
+    loop {
+        let len = read.read_u16().await? as usize;
+        let mut contents = vec![0; len];
+        read.read_exact(&mut contents).await?;
+        let packet = TcpPacket {
+            source,
+            destination,
+            contents,
+        };
+        if packets_tx.send(packet).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+async fn write_loop(mut write: OwnedWriteHalf, mut pending: UnboundedReceiver<Vec<u8>>) {
+    while let Some(contents) = pending.recv().await {
+        let Ok(len) = u16::try_from(contents.len()) else {
+            warn!(
+                "dropping {} byte ice-tcp packet, too large to frame",
+                contents.len()
+            );
+            continue;
+        };
+        let mut framed = Vec::with_capacity(2 + contents.len());
+        framed.extend_from_slice(&len.to_be_bytes());
+        framed.extend_from_slice(&contents);
+        if let Err(e) = write.write_all(&framed).await {
+            debug!("ice-tcp write failed: {:?}", e);
+            break;
+        }
+    }
+}
@@ -1,19 +1,40 @@
-use crate::player::render_video;
+use crate::player::{render_video, ScheduledSnapshot};
 use anyhow::{anyhow, Error, Result};
-use axum::{response::Response, routing::post, Router};
-use clap::{Args, Parser, Subcommand, ValueEnum};
-use encoder::{EncodedPacket, EncodedPacketIter, EncoderBuilder};
+use auth::Authenticator;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use client::WhipClaims;
+use config::{ConfigFile, Profile};
+use encoder::{Codec, EncodedAudioIter, EncodedPacket, EncodedPacketIter, EncoderBuilder};
 use ffmpeg_next::{frame, Rational};
+use ice_server::IceServer;
 use log::LevelFilter;
 use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
-use source::{AFScreenCapturer, DisplayDuplicator, Source};
-use std::{sync::mpsc, time::Instant};
+use source::{
+    AFScreenCapturer, AudioCapture, AudioSource, DisplayDuplicator, FilterGraphSource, MediaFile,
+    Source, StdinSource, V4l2Capturer, X11Capturer,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 use tokio::{sync::mpsc::UnboundedReceiver, task::JoinHandle};
 
+mod auth;
 mod client;
+mod config;
 mod encoder;
+mod ice_server;
+mod ice_tcp;
+mod overlay;
 mod player;
+mod sdpfrag;
+mod server;
 mod source;
+mod stats;
+mod stun;
+mod turn;
 mod whip;
 
 #[no_mangle]
@@ -29,12 +50,181 @@ struct SourceConfig {
     /// Device(s) to capture, source specific
     #[arg(short, long)]
     device: Option<String>,
+    /// Capture resolution, eg. 1280x720, source specific (required for raw stdin)
+    #[arg(long)]
+    size: Option<String>,
+    /// Capture pixel format, eg. yuyv422 or mjpeg, source specific
+    #[arg(long = "pix-fmt")]
+    pixel_format: Option<String>,
+    /// Restart from the beginning when a file source ends
+    #[arg(long = "loop")]
+    looping: bool,
+    /// lavfi filter graph for the lavfi source, eg. testsrc2=size=1280x720:rate=60
+    #[arg(long)]
+    filter: Option<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+struct EncoderConfig {
+    /// FFmpeg encoder name, eg. libx264, libopenh264, h264_nvenc
+    #[arg(long)]
+    encoder: Option<String>,
+    /// Target bitrate in kbit/s
+    #[arg(long)]
+    bitrate: Option<u32>,
+    /// Peak bitrate in kbit/s, also sizes the rate control buffer
+    #[arg(long)]
+    max_bitrate: Option<u32>,
+    /// Seconds between keyframes
+    #[arg(long, default_value_t = 2.0)]
+    gop_seconds: f32,
+    /// Encoder preset, encoder specific, eg. veryfast or p6
+    #[arg(long)]
+    preset: Option<String>,
+    /// Extra encoder AVOption, repeatable, eg. -o tune=film
+    #[arg(short = 'o', long = "encoder-option", value_parser = parse_key_value)]
+    options: Vec<(String, String)>,
+}
+
+impl EncoderConfig {
+    // Encoder AVOptions, merged over the codec defaults
+    fn options(&self) -> HashMap<String, String> {
+        let mut options = HashMap::new();
+        if let Some(bitrate) = self.bitrate {
+            options.insert("b".to_string(), format!("{bitrate}k"));
+        }
+        if let Some(max_bitrate) = self.max_bitrate {
+            options.insert("maxrate".to_string(), format!("{max_bitrate}k"));
+            options.insert("bufsize".to_string(), format!("{max_bitrate}k"));
+        }
+        if let Some(preset) = &self.preset {
+            options.insert("preset".to_string(), preset.clone());
+        }
+        options.extend(self.options.iter().cloned());
+        options
+    }
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum AudioCaptureMethod {
+    Pulse,
+    Alsa,
+    Lavfi,
+}
+
+#[derive(Debug, Clone, Args)]
+struct AudioConfig {
+    /// Capture and publish audio as Opus, alongside the video
+    #[arg(long, value_enum)]
+    audio: Option<AudioCaptureMethod>,
+    /// Audio device for pulse or alsa capture, eg. a monitor source for desktop sound
+    #[arg(long)]
+    audio_device: Option<String>,
+    /// lavfi audio graph for lavfi capture, defaults to sine=frequency=440
+    #[arg(long)]
+    audio_filter: Option<String>,
+    /// Opus bitrate in kbit/s
+    #[arg(long, default_value_t = 128)]
+    audio_bitrate: u32,
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum SnapshotFormat {
+    Png,
+    Jpeg,
+}
+
+#[derive(Debug, Clone, Args)]
+struct PlayerConfig {
+    /// Target video playout delay in milliseconds, absorbs network jitter
+    #[arg(long, default_value_t = 100)]
+    latency_ms: u64,
+
+    /// Directory snapshots are written to, press P in the player to take one
+    #[arg(long, default_value = ".")]
+    snapshot_dir: PathBuf,
+
+    /// Image format for snapshots
+    #[arg(long, value_enum, default_value_t = SnapshotFormat::Png)]
+    snapshot_format: SnapshotFormat,
+}
+
+#[derive(Debug, Clone, Args)]
+struct NetworkConfig {
+    /// STUN or TURN server, eg. turn:turn.example.com?transport=tcp or turns:..., repeatable.
+    /// Used along with any the WHIP/WHEP endpoint advertises
+    #[arg(long = "ice-server")]
+    ice_servers: Vec<String>,
+
+    /// Username for TURN servers given with --ice-server
+    #[arg(long)]
+    turn_username: Option<String>,
+
+    /// Credential for TURN servers given with --ice-server
+    #[arg(long)]
+    turn_credential: Option<String>,
+
+    /// Only gather candidates on this network interface, repeatable
+    #[arg(long = "interface")]
+    interfaces: Vec<String>,
+
+    /// Never gather candidates on this network interface, repeatable
+    #[arg(long = "exclude-interface")]
+    exclude_interfaces: Vec<String>,
+
+    /// Don't gather candidates on loopback addresses
+    #[arg(long)]
+    exclude_loopback: bool,
+
+    /// Gather candidates on IPv4 link-local (169.254.0.0/16) addresses
+    #[arg(long)]
+    include_link_local: bool,
+
+    /// Only gather IPv4 candidates
+    #[arg(long)]
+    no_ipv6: bool,
+
+    /// Don't offer ICE-TCP candidates, only UDP
+    #[arg(long)]
+    no_ice_tcp: bool,
+}
+
+impl NetworkConfig {
+    fn ice_servers(&self) -> Vec<IceServer> {
+        self.ice_servers
+            .iter()
+            .map(|url| IceServer {
+                url: url.clone(),
+                username: self.turn_username.clone(),
+                credential: self.turn_credential.clone(),
+            })
+            .collect()
+    }
+}
+
+fn parse_path(s: &str) -> Result<String> {
+    if !s.starts_with('/') {
+        return Err(anyhow!("path must start with /"));
+    }
+    Ok(s.to_string())
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected key=value, got `{s}`"))?;
+    Ok((key.to_string(), value.to_string()))
 }
 
 #[derive(Debug, Clone, ValueEnum)]
 pub enum CaptureMethod {
     AVFoundation,
     DXGI,
+    X11,
+    File,
+    Lavfi,
+    V4L2,
+    Stdin,
 }
 
 impl Default for CaptureMethod {
@@ -43,6 +233,9 @@ impl Default for CaptureMethod {
         return CaptureMethod::DXGI;
         #[cfg(target_os = "macos")]
         return CaptureMethod::AVFoundation;
+        #[cfg(target_os = "linux")]
+        return CaptureMethod::X11;
+        #[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
         panic!("unsupported platform")
     }
 }
@@ -57,29 +250,78 @@ struct Cli {
     /// Increase log verbosity, multiple occurrences (-vvv) further increase
     #[clap(short, global = true, action = clap::ArgAction::Count)]
     verbose: u8,
+
+    /// Configuration file, defaults to ./bitwhip.toml or ~/.config/bitwhip/bitwhip.toml
+    #[arg(long = "config", global = true)]
+    config_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Args)]
+struct StreamArgs {
+    /// The WHIP URL
+    url: Option<String>,
+
+    /// Capture method
+    #[clap(short, value_enum, default_value_t=CaptureMethod::default())]
+    capture_method: CaptureMethod,
+
+    #[command(flatten)]
+    config: SourceConfig,
+
+    #[command(flatten)]
+    encoder_config: EncoderConfig,
+
+    #[command(flatten)]
+    audio_config: AudioConfig,
+
+    #[command(flatten)]
+    network: NetworkConfig,
+
+    /// The WHIP bearer token
+    token: Option<String>,
+
+    /// Named profile from the configuration file, flags override its values
+    #[arg(long)]
+    profile: Option<String>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Stream to a WHIP destination
-    #[command(arg_required_else_help = true)]
-    Stream {
-        /// The WHIP URL
-        url: String,
+    Stream(StreamArgs),
+
+    /// Inspect the configuration file
+    #[command(subcommand)]
+    Config(ConfigCommands),
+
+    /// Start a WHIP server that accepts incoming requests
+    PlayWHIP {
+        /// Address to listen on, eg. 0.0.0.0:1337 or [::]:1337
+        #[arg(long, default_value = "0.0.0.0:1337")]
+        listen: SocketAddr,
+
+        /// Path WHIP offers are posted to, sessions are created beneath it
+        #[arg(long, default_value = "/", value_parser = parse_path)]
+        path: String,
 
-        /// Capture method
-        #[clap(short, value_enum, default_value_t=CaptureMethod::default())]
-        capture_method: CaptureMethod,
+        /// Static bearer token to accept, repeatable
+        #[arg(long = "token")]
+        tokens: Vec<String>,
+
+        /// Accept HMAC-SHA256 JWTs signed with this secret, see `token mint`
+        #[arg(long)]
+        jwt_secret: Option<String>,
 
         #[command(flatten)]
-        config: SourceConfig,
+        network: NetworkConfig,
 
-        /// The WHIP bearer token
-        token: Option<String>,
+        #[command(flatten)]
+        player: PlayerConfig,
     },
 
-    /// Start a WHIP server that accepts incoming requests
-    PlayWHIP {},
+    /// Issue bearer tokens for play-whip
+    #[command(subcommand)]
+    Token(TokenCommands),
 
     /// Play from a WHEP destination
     #[command(arg_required_else_help = true)]
@@ -89,14 +331,56 @@ enum Commands {
 
         /// The WHEP bearer token
         token: Option<String>,
+
+        #[command(flatten)]
+        network: NetworkConfig,
+
+        #[command(flatten)]
+        player: PlayerConfig,
+
+        /// Take a snapshot this many seconds after the first frame
+        #[arg(long)]
+        snapshot_after: Option<u64>,
+
+        /// Exit once the --snapshot-after snapshot is written, for scripted captures
+        #[arg(long, requires = "snapshot_after")]
+        exit: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum TokenCommands {
+    /// Print a JWT signed with the server's --jwt-secret, valid for a single session
+    Mint {
+        /// The WHIP URL the token is issued for
+        whip_url: String,
+
+        /// Shared HMAC secret, as passed to play-whip --jwt-secret
+        #[arg(long)]
+        secret: String,
+
+        /// Unique token id, random if not given
+        #[arg(long)]
+        jti: Option<String>,
+
+        /// Seconds until the token expires
+        #[arg(long, default_value_t = 3600)]
+        expires_in: u64,
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum ConfigCommands {
+    /// Print the effective stream configuration, after applying the profile and flags
+    Show(StreamArgs),
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     ffmpeg_next::init()?;
 
-    let args = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let args = Cli::from_arg_matches(&matches)?;
     let level_filter = match args.verbose {
         0 => LevelFilter::Warn,
         1 => LevelFilter::Info,
@@ -112,37 +396,130 @@ async fn main() -> Result<(), Error> {
     )?;
 
     match args.commands {
-        Commands::Stream {
+        Commands::Stream(stream_args) => {
+            let matches = matches.subcommand_matches("stream").unwrap();
+            stream(resolve_stream_args(stream_args, args.config_file, matches)?).await?
+        }
+        Commands::Config(ConfigCommands::Show(stream_args)) => {
+            let matches = matches
+                .subcommand_matches("config")
+                .and_then(|m| m.subcommand_matches("show"))
+                .unwrap();
+            let stream_args = resolve_stream_args(stream_args, args.config_file, matches)?;
+            print!("{}", toml::to_string_pretty(&Profile::from(&stream_args))?);
+        }
+        Commands::PlayWHIP {
+            listen,
+            path,
+            tokens,
+            jwt_secret,
+            network,
+            player,
+        } => {
+            let auth = Authenticator::new(tokens, jwt_secret)?;
+            server::play_whip(listen, path, auth, network, player).await?
+        }
+        Commands::Token(TokenCommands::Mint {
+            whip_url,
+            secret,
+            jti,
+            expires_in,
+        }) => {
+            let claims = WhipClaims {
+                whip_url,
+                jti: jti.unwrap_or_else(|| format!("{:032x}", rand::random::<u128>())),
+                exp: auth::unix_time() + expires_in,
+            };
+            println!("{}", auth::mint(&secret, &claims)?);
+        }
+        Commands::PlayWHEP {
             url,
             token,
-            capture_method,
-            config,
-        } => stream(url, token, capture_method, config).await?,
-        Commands::PlayWHIP {} => play_whip().await,
-        Commands::PlayWHEP { url, token } => play_whep(url, token).await?,
+            network,
+            player,
+            snapshot_after,
+            exit,
+        } => {
+            let scheduled = snapshot_after.map(|secs| ScheduledSnapshot {
+                after: Duration::from_secs(secs),
+                exit,
+            });
+            play_whep(url, token, network, player, scheduled).await?
+        }
     }
 
     Ok(())
 }
 
-async fn stream(
-    url: String,
-    token: Option<String>,
-    src: CaptureMethod,
-    config: SourceConfig,
-) -> Result<()> {
-    let (handle, rx) = match src {
-        CaptureMethod::AVFoundation => _stream(AFScreenCapturer::new(&config)?, &config),
-        CaptureMethod::DXGI => _stream(DisplayDuplicator::new()?, &config),
+// Merge the selected config file profile under the command line arguments
+fn resolve_stream_args(
+    mut args: StreamArgs,
+    config_file: Option<PathBuf>,
+    matches: &ArgMatches,
+) -> Result<StreamArgs> {
+    let profile = ConfigFile::load(config_file)?.profile(args.profile.as_deref())?;
+    profile.apply(&mut args, matches)?;
+    Ok(args)
+}
+
+async fn stream(args: StreamArgs) -> Result<()> {
+    let StreamArgs {
+        url,
+        token,
+        capture_method,
+        config,
+        encoder_config,
+        audio_config,
+        network,
+        ..
+    } = args;
+    let url = url.ok_or(anyhow!(
+        "missing WHIP URL, pass one or set url in a config profile"
+    ))?;
+
+    // Audio and video are timestamped against the same clock
+    let start = Instant::now();
+    let (handle, rx) = match capture_method {
+        CaptureMethod::AVFoundation => _stream(
+            AFScreenCapturer::new(&config)?,
+            &config,
+            &encoder_config,
+            start,
+        ),
+        CaptureMethod::DXGI => _stream(DisplayDuplicator::new()?, &config, &encoder_config, start),
+        CaptureMethod::X11 => _stream(X11Capturer::new(&config)?, &config, &encoder_config, start),
+        CaptureMethod::File => _stream(MediaFile::new(&config)?, &config, &encoder_config, start),
+        CaptureMethod::Lavfi => _stream(
+            FilterGraphSource::lavfi(&config)?,
+            &config,
+            &encoder_config,
+            start,
+        ),
+        CaptureMethod::V4L2 => {
+            _stream(V4l2Capturer::new(&config)?, &config, &encoder_config, start)
+        }
+        CaptureMethod::Stdin => {
+            _stream(StdinSource::new(&config)?, &config, &encoder_config, start)
+        }
         //_ =>  return Err(anyhow!("unsupported on this platform")),
         _ => panic!("unsupported capture_method for platform"),
     };
 
-    tokio::select! {
-        _ = whip::publish(&url, token, rx) => {},
-        res = handle => {
-            res??
+    let (audio_handle, audio_rx) = match &audio_config.audio {
+        Some(method) => {
+            let source = AudioCapture::new(method, &audio_config)?;
+            let (handle, rx) = _stream_audio(source, &audio_config, start);
+            (Some(handle), Some(rx))
         }
+        None => (None, None),
+    };
+
+    // Publishing ends on disconnect, Ctrl-C or once the source finishes.
+    // The encode threads stop at their next packet, surface any error from them
+    whip::publish(&url, token, &network, rx, audio_rx).await?;
+    handle.await??;
+    if let Some(handle) = audio_handle {
+        handle.await??;
     }
     Ok(())
 }
@@ -150,31 +527,46 @@ async fn stream(
 fn _stream<T>(
     mut source: T,
     config: &SourceConfig,
+    encoder_config: &EncoderConfig,
+    start: Instant,
 ) -> (JoinHandle<Result<()>>, UnboundedReceiver<EncodedPacket>)
 where
     T: Source + Send + 'static,
 {
     let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
     let frame_rate = Rational::new(config.framerate, 1);
+    let codec = encoder_config.encoder.clone().map(Codec::from);
+    let options = encoder_config.options();
+    let gop = (encoder_config.gop_seconds * config.framerate as f32)
+        .round()
+        .max(1.0) as u32;
     let join_handle = tokio::task::spawn_blocking(move || -> Result<()> {
-        let encoder = EncoderBuilder::new()
+        let mut builder = EncoderBuilder::new();
+        if let Some(codec) = codec {
+            builder = builder.set_encoder(codec);
+        }
+        let encoder = builder
             .for_source(&mut source)
+            .set_options(options)
             .customise(move |encoder| {
                 encoder.set_frame_rate(Some(frame_rate));
                 encoder.set_time_base(frame_rate.invert());
-                encoder.set_gop(120);
+                encoder.set_gop(gop);
                 encoder.set_max_b_frames(0);
             })
             .open()?;
 
-        let mut iter = EncodedPacketIter::new(encoder, source);
+        let mut iter = EncodedPacketIter::new(encoder, source, start);
         loop {
             match iter.next() {
                 Some(Err(e)) => {
                     return Err(e);
                 }
                 Some(Ok(packet)) => {
-                    tx.send(packet).unwrap();
+                    // Publisher has gone away
+                    if tx.send(packet).is_err() {
+                        break;
+                    }
                 }
                 None => break,
             }
@@ -185,45 +577,45 @@ where
     (join_handle, rx)
 }
 
-async fn whip_handler(
-    tx: mpsc::Sender<ffmpeg_next::frame::Video>,
-    offer: String,
-) -> Response<String> {
-    let answer = whip::subscribe_as_server(tx, offer);
-    Response::builder()
-        .status(201)
-        .header("Location", "/")
-        .body(answer)
-        .unwrap()
-}
-
-async fn play_whip() {
-    println!("Listening for WHIP Requests on 0.0.0.0:1337");
-    let (tx, rx): (
-        mpsc::Sender<ffmpeg_next::frame::Video>,
-        mpsc::Receiver<ffmpeg_next::frame::Video>,
-    ) = mpsc::channel();
-
-    tokio::task::spawn(async move {
-        axum::serve(
-            tokio::net::TcpListener::bind("0.0.0.0:1337").await.unwrap(),
-            Router::new().route("/", post(move |offer: String| whip_handler(tx, offer))),
-        )
-        .await
-        .unwrap();
+fn _stream_audio<T>(
+    source: T,
+    config: &AudioConfig,
+    start: Instant,
+) -> (JoinHandle<Result<()>>, UnboundedReceiver<EncodedPacket>)
+where
+    T: AudioSource + Send + 'static,
+{
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let bitrate = config.audio_bitrate;
+    let join_handle = tokio::task::spawn_blocking(move || -> Result<()> {
+        let encoder = encoder::open_opus(bitrate)?;
+        for packet in EncodedAudioIter::new(encoder, source, start) {
+            // Publisher has gone away
+            if tx.send(packet?).is_err() {
+                break;
+            }
+        }
+        Ok(())
     });
 
-    render_video(rx);
+    (join_handle, rx)
 }
 
-async fn play_whep(url: String, token: Option<String>) -> Result<()> {
-    let (tx, rx): (
-        mpsc::Sender<ffmpeg_next::frame::Video>,
-        mpsc::Receiver<ffmpeg_next::frame::Video>,
-    ) = mpsc::channel();
+async fn play_whep(
+    url: String,
+    token: Option<String>,
+    network: NetworkConfig,
+    player_config: PlayerConfig,
+    scheduled: Option<ScheduledSnapshot>,
+) -> Result<()> {
+    let (tx, rx) = player::channel();
+
+    let session = whip::subscribe_as_client(tx, &url, token, &network).await?;
+    render_video(rx, &player_config, scheduled);
 
-    whip::subscribe_as_client(tx, &url, token).await;
-    render_video(rx);
+    // Window closed, tear down the WHEP session before exiting
+    let _ = session.commands.send(whip::SessionCommand::Close);
+    session.task.await?;
 
     Ok(())
 }
@@ -1,11 +1,464 @@
-use sdl2::event::Event;
+use crate::{
+    encoder, overlay, source::PixelConverter, stats::SessionStats, PlayerConfig, SnapshotFormat,
+};
+use ffmpeg_next::{codec, format::Pixel, frame};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::PixelFormatEnum;
-use sdl2::video::WindowBuilder;
-use sdl2::VideoSubsystem;
+use sdl2::mouse::MouseButton;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::sys::SDL_bool;
+use sdl2::video::{FullscreenType, Window, WindowBuilder};
+use sdl2::{Sdl, VideoSubsystem};
+use std::collections::VecDeque;
 use std::io::Write;
+use std::path::PathBuf;
 use std::sync::mpsc;
-use tracing::error;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+const AUDIO_RATE: i32 = 48000;
+const AUDIO_CHANNELS: u8 = 2;
+// Audio queued before playback starts, grown on underruns and slowly shrunk
+const AUDIO_MIN_BUFFER: Duration = Duration::from_millis(40);
+const AUDIO_MAX_BUFFER: Duration = Duration::from_millis(200);
+// Beyond this much queued we drop audio to catch up
+const AUDIO_MAX_QUEUED: Duration = Duration::from_millis(300);
+const VOLUME_STEP: f32 = 0.1;
+// Video this far behind the audio is dropped, this far ahead is held back
+const VIDEO_LATE: Duration = Duration::from_millis(80);
+const VIDEO_EARLY: Duration = Duration::from_millis(20);
+// Don't hold video forever if the clocks disagree wildly
+const VIDEO_MAX_HOLD: Duration = Duration::from_secs(1);
+// How much sooner each frame is shown while catching up on a grown buffer
+const PLAYOUT_CATCH_UP: Duration = Duration::from_millis(5);
+
+const HELP: &[&str] = &[
+    "F, double click   fullscreen",
+    "1                 1:1 pixels",
+    "T                 always on top",
+    "S                 stats",
+    "P                 snapshot",
+    "M                 mute",
+    "+/-, up/down      volume",
+    "?                 this help",
+    "Esc               quit",
+];
+
+// Interleaved stereo f32 samples at 48kHz
+pub struct AudioChunk {
+    pub samples: Vec<f32>,
+    // Sender wall clock of the first sample, once a sender report maps it
+    pub capture_time: Option<Instant>,
+}
+
+pub struct VideoFrame {
+    pub frame: frame::Video,
+    pub rtp_time: Duration,
+    // Sender wall clock of the frame, once a sender report maps it
+    pub capture_time: Option<Instant>,
+}
+
+// Snapshot taken automatically once playback has run for a while
+pub struct ScheduledSnapshot {
+    pub after: Duration,
+    // Close the player once it's written
+    pub exit: bool,
+}
+
+// Decoded media from a session to the player
+#[derive(Clone)]
+pub struct PlayerTx {
+    pub video: mpsc::Sender<VideoFrame>,
+    pub audio: mpsc::Sender<AudioChunk>,
+    pub stats: mpsc::Sender<SessionStats>,
+}
+
+pub struct PlayerRx {
+    pub video: mpsc::Receiver<VideoFrame>,
+    pub audio: mpsc::Receiver<AudioChunk>,
+    pub stats: mpsc::Receiver<SessionStats>,
+}
+
+pub fn channel() -> (PlayerTx, PlayerRx) {
+    let (video_tx, video_rx) = mpsc::channel();
+    let (audio_tx, audio_rx) = mpsc::channel();
+    let (stats_tx, stats_rx) = mpsc::channel();
+    (
+        PlayerTx {
+            video: video_tx,
+            audio: audio_tx,
+            stats: stats_tx,
+        },
+        PlayerRx {
+            video: video_rx,
+            audio: audio_rx,
+            stats: stats_rx,
+        },
+    )
+}
+
+fn audio_bytes(duration: Duration) -> u32 {
+    let bytes_per_second = AUDIO_RATE as u32 * AUDIO_CHANNELS as u32 * 4;
+    (duration.as_secs_f64() * bytes_per_second as f64) as u32
+}
+
+fn audio_duration(bytes: u32) -> Duration {
+    let bytes_per_second = AUDIO_RATE as u32 * AUDIO_CHANNELS as u32 * 4;
+    Duration::from_secs_f64(bytes as f64 / bytes_per_second as f64)
+}
+
+// Signed difference in milliseconds, for logging
+fn offset_ms(a: Instant, b: Instant) -> i64 {
+    match a.checked_duration_since(b) {
+        Some(d) => d.as_millis() as i64,
+        None => -(b.duration_since(a).as_millis() as i64),
+    }
+}
+
+// SDL audio queue with a small buffer that adapts to network jitter
+struct AudioPlayer {
+    queue: AudioQueue<f32>,
+    volume: f32,
+    muted: bool,
+    // Paused and filling up to target before (re)starting playback
+    buffering: bool,
+    target: Duration,
+    last_underrun: Instant,
+    dropped: u64,
+    // Sender wall clock at the end of the queued audio
+    queued_until: Option<Instant>,
+}
+
+impl AudioPlayer {
+    fn new(sdl_context: &Sdl) -> Result<Self, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(AUDIO_RATE),
+            channels: Some(AUDIO_CHANNELS),
+            samples: Some(480),
+        };
+        let queue = sdl_context.audio()?.open_queue::<f32, _>(None, &desired)?;
+        Ok(Self {
+            queue,
+            volume: 1.0,
+            muted: false,
+            buffering: true,
+            target: AUDIO_MIN_BUFFER,
+            last_underrun: Instant::now(),
+            dropped: 0,
+            queued_until: None,
+        })
+    }
+
+    fn push(&mut self, mut chunk: AudioChunk) {
+        if self.queue.size() > audio_bytes(AUDIO_MAX_QUEUED) {
+            self.dropped += 1;
+            return;
+        }
+
+        let gain = if self.muted { 0.0 } else { self.volume };
+        if gain != 1.0 {
+            chunk.samples.iter_mut().for_each(|s| *s *= gain);
+        }
+        if let Err(e) = self.queue.queue_audio(&chunk.samples) {
+            error!("Error queueing audio: {}", e);
+        }
+        self.queued_until = chunk
+            .capture_time
+            .map(|t| t + audio_duration(chunk.samples.len() as u32 * 4));
+
+        if self.buffering && self.queue.size() >= audio_bytes(self.target) {
+            self.buffering = false;
+            self.queue.resume();
+        }
+    }
+
+    // Called every loop, restarts buffering when playback ran dry
+    fn poll(&mut self) {
+        if !self.buffering && self.queue.size() == 0 {
+            self.buffering = true;
+            self.queue.pause();
+            self.target = (self.target + Duration::from_millis(20)).min(AUDIO_MAX_BUFFER);
+            self.last_underrun = Instant::now();
+            warn!(
+                "audio underrun, buffering {}ms ({} chunks dropped so far)",
+                self.target.as_millis(),
+                self.dropped
+            );
+        } else if self.last_underrun.elapsed() > Duration::from_secs(10) {
+            // Stable for a while, trade some safety for latency
+            self.target = self
+                .target
+                .saturating_sub(Duration::from_millis(10))
+                .max(AUDIO_MIN_BUFFER);
+            self.last_underrun = Instant::now();
+        }
+    }
+
+    // Sender wall clock of the audio being heard right now, the master clock
+    fn clock(&self) -> Option<Instant> {
+        if self.buffering {
+            return None;
+        }
+        let queued = audio_duration(self.queue.size());
+        self.queued_until?.checked_sub(queued)
+    }
+
+    fn toggle_mute(&mut self) {
+        self.muted = !self.muted;
+        info!("audio {}", if self.muted { "muted" } else { "unmuted" });
+    }
+
+    fn change_volume(&mut self, delta: f32) {
+        self.volume = (self.volume + delta).clamp(0.0, 2.0);
+        info!("volume {:.0}%", self.volume * 100.0);
+    }
+}
+
+// Playout buffer for decoded video. Frames are paced by RTP timestamp,
+// shown `latency` after the first one arrived, or by the audio clock once
+// sender reports line the two up
+struct Playout {
+    pending: VecDeque<VideoFrame>,
+    latency: Duration,
+    // Local time a given RTP time is due
+    anchor: Option<(Instant, Duration)>,
+    late: u64,
+    dropped: u64,
+    last_log: Instant,
+}
+
+impl Playout {
+    fn new(latency: Duration) -> Self {
+        Self {
+            pending: VecDeque::new(),
+            latency,
+            anchor: None,
+            late: 0,
+            dropped: 0,
+            last_log: Instant::now(),
+        }
+    }
+
+    fn due(&self, rtp_time: Duration) -> Option<Instant> {
+        let (at, anchor_rtp) = self.anchor?;
+        match rtp_time.checked_sub(anchor_rtp) {
+            Some(d) => at.checked_add(d),
+            None => at.checked_sub(anchor_rtp - rtp_time),
+        }
+    }
+
+    fn push(&mut self, frame: VideoFrame, now: Instant) {
+        match self.due(frame.rtp_time) {
+            None => self.anchor = Some((now + self.latency, frame.rtp_time)),
+            Some(due) if due < now => {
+                self.late += 1;
+                // Stalled for longer than the buffer covers, start over
+                if now - due > self.latency {
+                    warn!("video {}ms late, rebuffering", (now - due).as_millis());
+                    self.anchor = Some((now + self.latency, frame.rtp_time));
+                }
+            }
+            Some(_) => {}
+        }
+        self.pending.push_back(frame);
+    }
+
+    fn next(&mut self, clock: Option<Instant>, now: Instant) -> Option<VideoFrame> {
+        self.log(clock, now);
+        match clock {
+            Some(clock) if self.pending.front()?.capture_time.is_some() => self.next_synced(clock),
+            _ => self.next_paced(now),
+        }
+    }
+
+    // Audio is the master clock, hold or drop video to match it
+    fn next_synced(&mut self, clock: Instant) -> Option<VideoFrame> {
+        // Too late to be worth showing, unless it's all we have
+        while self.pending.len() > 1 {
+            match self.pending[0].capture_time {
+                Some(t) if t + VIDEO_LATE < clock => {
+                    self.pending.pop_front();
+                    self.dropped += 1;
+                }
+                _ => break,
+            }
+        }
+
+        match self.pending.front()?.capture_time {
+            Some(t) if t > clock + VIDEO_EARLY && t < clock + VIDEO_MAX_HOLD => None,
+            _ => self.pending.pop_front(),
+        }
+    }
+
+    // No audio to follow, show frames at their RTP time after the latency
+    fn next_paced(&mut self, now: Instant) -> Option<VideoFrame> {
+        while self.pending.len() > 1 {
+            match self.due(self.pending[0].rtp_time) {
+                Some(due) if due + VIDEO_LATE < now => {
+                    self.pending.pop_front();
+                    self.dropped += 1;
+                }
+                _ => break,
+            }
+        }
+
+        if let Some(due) = self.due(self.pending.front()?.rtp_time) {
+            if due > now {
+                return None;
+            }
+        }
+        self.catch_up(now);
+        self.pending.pop_front()
+    }
+
+    // Buffer grew past twice the target, play a little faster to catch up.
+    // Called once per frame shown so the speed up doesn't depend on how
+    // often the render loop polls
+    fn catch_up(&mut self, now: Instant) {
+        let (Some(back), Some((at, rtp))) = (self.pending.back(), self.anchor) else {
+            return;
+        };
+        let buffered = self
+            .due(back.rtp_time)
+            .map(|due| due.saturating_duration_since(now));
+        if buffered.is_some_and(|b| b > self.latency * 2) {
+            if let Some(at) = at.checked_sub(PLAYOUT_CATCH_UP) {
+                self.anchor = Some((at, rtp));
+            }
+        }
+    }
+
+    fn log(&mut self, clock: Option<Instant>, now: Instant) {
+        if now - self.last_log < Duration::from_secs(1) {
+            return;
+        }
+        self.last_log = now;
+
+        let buffered = match (self.pending.front(), self.pending.back()) {
+            (Some(front), Some(back)) => back.rtp_time.saturating_sub(front.rtp_time),
+            _ => Duration::ZERO,
+        };
+        debug!(
+            "video playout: {} frames ({}ms) buffered, {} late, {} dropped",
+            self.pending.len(),
+            buffered.as_millis(),
+            self.late,
+            self.dropped
+        );
+        if let (Some(clock), Some(t)) = (clock, self.pending.front().and_then(|f| f.capture_time)) {
+            debug!("a/v offset {:+}ms", offset_ms(t, clock));
+        }
+    }
+}
+
+// How the video is fitted into the window
+#[derive(Default)]
+struct View {
+    fullscreen: bool,
+    // Show video pixels 1:1 rather than scaled to fit
+    native: bool,
+    on_top: bool,
+    help: bool,
+    stats: bool,
+}
+
+impl View {
+    fn toggle_fullscreen(&mut self, window: &mut Window) {
+        self.fullscreen = !self.fullscreen;
+        let mode = if self.fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Off
+        };
+        if let Err(e) = window.set_fullscreen(mode) {
+            warn!("failed to toggle fullscreen: {}", e);
+        }
+    }
+
+    fn toggle_on_top(&mut self, window: &Window) {
+        self.on_top = !self.on_top;
+        let on_top = if self.on_top {
+            SDL_bool::SDL_TRUE
+        } else {
+            SDL_bool::SDL_FALSE
+        };
+        unsafe { sdl2::sys::SDL_SetWindowAlwaysOnTop(window.raw(), on_top) };
+        info!("always on top {}", if self.on_top { "on" } else { "off" });
+    }
+
+    // Where the video goes on the canvas, letterboxed to keep its aspect ratio
+    fn video_rect(&self, (out_w, out_h): (u32, u32), (w, h): (u32, u32)) -> Rect {
+        let (w, h) = if self.native {
+            (w, h)
+        } else {
+            let scale = f64::min(out_w as f64 / w as f64, out_h as f64 / h as f64);
+            (
+                ((w as f64 * scale).round() as u32).max(1),
+                ((h as f64 * scale).round() as u32).max(1),
+            )
+        };
+        Rect::from_center(Rect::new(0, 0, out_w, out_h).center(), w, h)
+    }
+}
+
+// Latest session stats plus what only the player knows
+struct StatsView {
+    session: SessionStats,
+    decoded: u64,
+    fps: f64,
+    since: Instant,
+}
+
+impl StatsView {
+    fn new() -> Self {
+        Self {
+            session: SessionStats::default(),
+            decoded: 0,
+            fps: 0.0,
+            since: Instant::now(),
+        }
+    }
+
+    // Recalculates the decoded frame rate once a second, true if it did
+    fn tick(&mut self, now: Instant) -> bool {
+        let elapsed = now - self.since;
+        if elapsed < Duration::from_secs(1) {
+            return false;
+        }
+        self.fps = self.decoded as f64 / elapsed.as_secs_f64();
+        self.decoded = 0;
+        self.since = now;
+        true
+    }
+
+    fn lines(&self, playout: &Playout, (width, height): (u32, u32)) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{}x{}  {:.1} fps  {} late  {} dropped",
+            width, height, self.fps, playout.late, playout.dropped
+        )];
+        for stream in &self.session.streams {
+            let codec = stream.codec.map_or("-".to_string(), |c| format!("{:?}", c));
+            let loss = stream
+                .loss
+                .map_or("-".to_string(), |l| format!("{:.1}%", l * 100.0));
+            let rtt = stream
+                .rtt
+                .map_or("-".to_string(), |r| format!("{}ms", r.as_millis()));
+            lines.push(format!(
+                "{}  {:.0} kbit/s  loss {}  jitter {}ms  rtt {}",
+                codec,
+                stream.kbps,
+                loss,
+                stream.jitter.as_millis(),
+                rtt
+            ));
+        }
+        lines.push(format!("total {:.0} kbit/s", self.session.total_kbps));
+        lines
+    }
+}
 
 fn create_window(s: VideoSubsystem, height: u32, width: u32) -> WindowBuilder {
     let title = "bitwhip";
@@ -22,13 +475,75 @@ fn create_window(s: VideoSubsystem, height: u32, width: u32) -> WindowBuilder {
     return s.window(title, width, height);
 }
 
-pub fn render_video(rx: mpsc::Receiver<ffmpeg_next::frame::Video>) {
-    match rx.recv() {
-        Ok(first_frame) => {
+// Write the frame to a timestamped file in the snapshot directory
+fn save_snapshot(frame: &frame::Video, config: &PlayerConfig) -> anyhow::Result<PathBuf> {
+    let (extension, id, pixel) = match config.snapshot_format {
+        SnapshotFormat::Png => ("png", codec::Id::PNG, Pixel::RGB24),
+        SnapshotFormat::Jpeg => ("jpg", codec::Id::MJPEG, Pixel::YUVJ420P),
+    };
+    let image = encoder::encode_image(frame, id, pixel)?;
+    let name = format!(
+        "bitwhip-{}.{}",
+        chrono::Local::now().format("%Y%m%d-%H%M%S%.3f"),
+        extension
+    );
+    let path = config.snapshot_dir.join(name);
+    std::fs::create_dir_all(&config.snapshot_dir)?;
+    std::fs::write(&path, image)?;
+    Ok(path)
+}
+
+fn snapshot(frame: &frame::Video, config: &PlayerConfig) {
+    if frame.width() == 0 {
+        warn!("no frame shown yet, nothing to snapshot");
+        return;
+    }
+    match save_snapshot(frame, config) {
+        Ok(path) => info!("saved snapshot {}", path.display()),
+        Err(e) => error!("Error saving snapshot: {:?}", e),
+    }
+}
+
+// Copy a YUV420P frame into the IYUV texture buffer
+fn copy_planes(frame: &frame::Video, mut buffer: &mut [u8]) -> bool {
+    let Some(desc) = frame.format().descriptor() else {
+        return false;
+    };
+    let frame_ptr = unsafe { *frame.as_ptr() };
+
+    // Copy to buffer, trim padding
+    for p in 0..frame.planes() {
+        frame
+            .data(p)
+            .chunks_exact(frame_ptr.linesize[p] as usize)
+            .for_each(|row| {
+                let scale = match p {
+                    0 => 0,
+                    _ => desc.log2_chroma_w(),
+                };
+                let (a, _) =
+                    row.split_at(((frame.width() + (1 << scale) - 1) >> scale as u32) as usize);
+                if let Err(e) = buffer.write(a) {
+                    error!("Error writing frame to texture: {}", e)
+                }
+            });
+    }
+    true
+}
+
+pub fn render_video(rx: PlayerRx, config: &PlayerConfig, mut scheduled: Option<ScheduledSnapshot>) {
+    match rx.video.recv() {
+        Ok(first) => {
+            let first_frame = &first.frame;
             let sdl_context = sdl2::init().unwrap();
+            // Playing video without sound beats not playing at all
+            let mut audio = AudioPlayer::new(&sdl_context)
+                .map_err(|e| warn!("no audio output: {}", e))
+                .ok();
             let video_subsystem = sdl_context.video().unwrap();
             let window = create_window(video_subsystem, first_frame.height(), first_frame.width())
                 .position_centered()
+                .resizable()
                 .build()
                 .unwrap();
 
@@ -43,8 +558,19 @@ pub fn render_video(rx: mpsc::Receiver<ffmpeg_next::frame::Video>) {
                 )
                 .map_err(|e| e.to_string())
                 .expect("No error");
+            let mut texture_size = (first_frame.width(), first_frame.height());
+            // Decoders may hand us NV12, YUV444 or 10-bit, the texture is IYUV
+            let mut converter = PixelConverter::default();
+            let mut converted = frame::Video::empty();
+            let mut view = View::default();
+            let mut stats = StatsView::new();
+            let mut snapshot_due: Option<Instant> = None;
+            let mut playout = Playout::new(Duration::from_millis(config.latency_ms));
+            playout.push(first, Instant::now());
 
             'running: loop {
+                // Window changes need the current frame drawn again
+                let mut redraw = false;
                 for event in event_pump.poll_iter() {
                     match event {
                         Event::Quit { .. }
@@ -52,50 +578,154 @@ pub fn render_video(rx: mpsc::Receiver<ffmpeg_next::frame::Video>) {
                             keycode: Some(Keycode::Escape),
                             ..
                         } => break 'running,
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F),
+                            ..
+                        }
+                        | Event::MouseButtonDown {
+                            mouse_btn: MouseButton::Left,
+                            clicks: 2,
+                            ..
+                        } => {
+                            view.toggle_fullscreen(canvas.window_mut());
+                            redraw = true;
+                        }
+                        Event::KeyDown {
+                            keycode: Some(Keycode::Num1 | Keycode::Kp1),
+                            ..
+                        } => {
+                            view.native = !view.native;
+                            redraw = true;
+                        }
+                        Event::KeyDown {
+                            keycode: Some(Keycode::T),
+                            ..
+                        } => view.toggle_on_top(canvas.window()),
+                        Event::KeyDown {
+                            keycode: Some(Keycode::Slash | Keycode::Question | Keycode::F1),
+                            ..
+                        } => {
+                            view.help = !view.help;
+                            redraw = true;
+                        }
+                        Event::KeyDown {
+                            keycode: Some(Keycode::S),
+                            ..
+                        } => {
+                            view.stats = !view.stats;
+                            redraw = true;
+                        }
+                        Event::KeyDown {
+                            keycode: Some(Keycode::P),
+                            ..
+                        } => snapshot(&converted, config),
+                        Event::Window {
+                            win_event: WindowEvent::SizeChanged(..) | WindowEvent::Exposed,
+                            ..
+                        } => redraw = true,
+                        Event::KeyDown {
+                            keycode: Some(keycode),
+                            ..
+                        } => match (keycode, audio.as_mut()) {
+                            (Keycode::M, Some(audio)) => audio.toggle_mute(),
+                            (Keycode::Up | Keycode::Plus | Keycode::KpPlus, Some(audio)) => {
+                                audio.change_volume(VOLUME_STEP)
+                            }
+                            (Keycode::Down | Keycode::Minus | Keycode::KpMinus, Some(audio)) => {
+                                audio.change_volume(-VOLUME_STEP)
+                            }
+                            _ => {}
+                        },
                         _ => {}
                     }
                 }
 
-                let res = texture
-                    .with_lock(None, |mut buffer: &mut [u8], _pitch: usize| {
-                        match rx.try_recv() {
-                            Ok(frame) => unsafe {
-                                let Some(desc) = frame.format().descriptor() else {
-                                    return false;
-                                };
-                                let frame_ptr = *frame.as_ptr();
-
-                                // Copy to buffer, trim padding
-                                for p in 0..frame.planes() {
-                                    frame
-                                        .data(p)
-                                        .chunks_exact(frame_ptr.linesize[p] as usize)
-                                        .for_each(|row| {
-                                            let scale = match p {
-                                                0 => 0,
-                                                _ => desc.log2_chroma_w(),
-                                            };
-                                            let (a, _) = row.split_at(
-                                                ((frame.width() + (1 << scale) - 1) >> scale as u32)
-                                                    as usize,
-                                            );
-                                            if let Err(e) = buffer.write(a) {
-                                                error!("Error writing frame to texture: {}", e)
-                                            }
-                                        });
+                while let Ok(chunk) = rx.audio.try_recv() {
+                    if let Some(audio) = audio.as_mut() {
+                        audio.push(chunk);
+                    }
+                }
+                if let Some(audio) = audio.as_mut() {
+                    audio.poll();
+                }
+
+                let now = Instant::now();
+                while let Ok(frame) = rx.video.try_recv() {
+                    stats.decoded += 1;
+                    playout.push(frame, now);
+                }
+                while let Ok(session) = rx.stats.try_recv() {
+                    stats.session = session;
+                }
+                redraw |= stats.tick(now) && view.stats;
+                let next = playout.next(audio.as_ref().and_then(|a| a.clock()), now);
+
+                let res = match next {
+                    Some(VideoFrame { mut frame, .. }) => {
+                        match converter.run(&mut frame, &mut converted) {
+                            Ok(()) => {
+                                let size = (converted.width(), converted.height());
+                                // The publisher switched resolution, eg. to another monitor
+                                if size != texture_size {
+                                    info!("video resolution changed to {}x{}", size.0, size.1);
+                                    texture = texture_creator
+                                        .create_texture_streaming(
+                                            PixelFormatEnum::IYUV,
+                                            size.0,
+                                            size.1,
+                                        )
+                                        .map_err(|e| e.to_string())
+                                        .expect("No error");
+                                    texture_size = size;
                                 }
-                                true
-                            },
-                            Err(_err) => false,
+                                texture
+                                    .with_lock(None, |buffer: &mut [u8], _pitch: usize| {
+                                        copy_planes(&converted, buffer)
+                                    })
+                                    .expect("texture copy")
+                            }
+                            Err(e) => {
+                                error!("Error converting frame: {}", e);
+                                false
+                            }
                         }
-                    })
-                    .expect("texture copy");
+                    }
+                    None => false,
+                };
 
-                if res {
+                if res || redraw {
+                    let output = canvas.output_size().expect("No error");
+                    canvas.set_draw_color(Color::BLACK);
                     canvas.clear();
-                    canvas.copy(&texture, None, None).expect("No error");
+                    canvas
+                        .copy(&texture, None, view.video_rect(output, texture_size))
+                        .expect("No error");
+                    let scale = overlay::text_scale(output.1);
+                    if view.help {
+                        if let Err(e) = overlay::draw_panel(&mut canvas, 0, 0, HELP, scale) {
+                            error!("Error drawing help: {}", e);
+                        }
+                    }
+                    if view.stats {
+                        let lines = stats.lines(&playout, texture_size);
+                        let x = output.0 as i32 - overlay::panel_size(&lines, scale).0 as i32;
+                        if let Err(e) = overlay::draw_panel(&mut canvas, x, 0, &lines, scale) {
+                            error!("Error drawing stats: {}", e);
+                        }
+                    }
                     canvas.present();
                 }
+
+                if let (Some(scheduled), true) = (&scheduled, res) {
+                    snapshot_due.get_or_insert(now + scheduled.after);
+                }
+                if snapshot_due.is_some_and(|due| now >= due) {
+                    snapshot(&converted, config);
+                    snapshot_due = None;
+                    if scheduled.take().is_some_and(|s| s.exit) {
+                        break 'running;
+                    }
+                }
             }
         }
         Err(_err) => {}
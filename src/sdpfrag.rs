@@ -0,0 +1,213 @@
+use std::fmt::{self, Write};
+
+pub const CONTENT_TYPE: &str = "application/trickle-ice-sdpfrag";
+
+// An SDP fragment (RFC 8840) as carried in WHIP/WHEP PATCH requests, used
+// for trickling candidates and ICE restarts
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SdpFragment {
+    pub ice_ufrag: Option<String>,
+    pub ice_pwd: Option<String>,
+    // Candidates apply to the whole BUNDLE group, so they go under its first
+    // media section, the bundle tag in WebRTC offers and answers (RFC 8843).
+    // Its m= line, which a fragment has to repeat, and its mid
+    pub media: Option<String>,
+    pub mid: Option<String>,
+    // Candidate attribute values, eg. "candidate:1 1 udp 2130706431 ..."
+    pub candidates: Vec<String>,
+    pub end_of_candidates: bool,
+}
+
+impl SdpFragment {
+    pub fn parse(s: &str) -> Self {
+        let mut frag = Self::default();
+        let mut media = None;
+        for line in s.lines().map(str::trim) {
+            if line.starts_with("m=") {
+                media = Some(line);
+            } else if let Some(ufrag) = line.strip_prefix("a=ice-ufrag:") {
+                frag.ice_ufrag = Some(ufrag.to_string());
+            } else if let Some(pwd) = line.strip_prefix("a=ice-pwd:") {
+                frag.ice_pwd = Some(pwd.to_string());
+            } else if let Some(mid) = line.strip_prefix("a=mid:") {
+                if frag.mid.is_none() {
+                    frag.mid = Some(mid.to_string());
+                    frag.media = media.map(str::to_string);
+                }
+            } else if let Some(candidate) = line.strip_prefix("a=") {
+                if candidate.starts_with("candidate:") {
+                    frag.candidates.push(candidate.to_string());
+                } else if candidate == "end-of-candidates" {
+                    frag.end_of_candidates = true;
+                }
+            }
+        }
+        frag
+    }
+
+    // Rewrite a full SDP with this fragment's credentials and candidates, so
+    // an ICE restart can go through the usual offer/answer handling
+    pub fn apply_to(&self, sdp: &str) -> String {
+        let mut out = String::with_capacity(sdp.len());
+        for line in sdp.lines().map(str::trim_end) {
+            if line.starts_with("a=candidate:") || line == "a=end-of-candidates" {
+                continue;
+            }
+            if let (Some(_), Some(ufrag)) = (line.strip_prefix("a=ice-ufrag:"), &self.ice_ufrag) {
+                write!(out, "a=ice-ufrag:{ufrag}\r\n").unwrap();
+                continue;
+            }
+            if let Some(pwd) = line.strip_prefix("a=ice-pwd:") {
+                let pwd = self.ice_pwd.as_deref().unwrap_or(pwd);
+                write!(out, "a=ice-pwd:{pwd}\r\n").unwrap();
+                // Candidates belong with the credentials of each media section
+                for candidate in &self.candidates {
+                    write!(out, "a={candidate}\r\n").unwrap();
+                }
+                if self.end_of_candidates {
+                    write!(out, "a=end-of-candidates\r\n").unwrap();
+                }
+                continue;
+            }
+            write!(out, "{line}\r\n").unwrap();
+        }
+        out
+    }
+}
+
+// Session level ICE credentials from a full SDP offer or answer
+pub fn ice_credentials(sdp: &str) -> Option<(String, String)> {
+    let frag = SdpFragment::parse(sdp);
+    Some((frag.ice_ufrag?, frag.ice_pwd?))
+}
+
+impl fmt::Display for SdpFragment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ufrag) = &self.ice_ufrag {
+            write!(f, "a=ice-ufrag:{ufrag}\r\n")?;
+        }
+        if let Some(pwd) = &self.ice_pwd {
+            write!(f, "a=ice-pwd:{pwd}\r\n")?;
+        }
+        if let Some(media) = &self.media {
+            write!(f, "{media}\r\n")?;
+        }
+        if let Some(mid) = &self.mid {
+            write!(f, "a=mid:{mid}\r\n")?;
+        }
+        for candidate in &self.candidates {
+            write!(f, "a={candidate}\r\n")?;
+        }
+        if self.end_of_candidates {
+            write!(f, "a=end-of-candidates\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r
+o=- 1 1 IN IP4 0.0.0.0\r
+s=-\r
+t=0 0\r
+a=group:BUNDLE 0 1\r
+m=video 9 UDP/TLS/RTP/SAVPF 96\r
+c=IN IP4 0.0.0.0\r
+a=ice-ufrag:old\r
+a=ice-pwd:oldpassword\r
+a=candidate:1 1 udp 2130706431 192.0.2.1 5000 typ host\r
+a=end-of-candidates\r
+a=mid:0\r
+a=rtpmap:96 H264/90000\r
+m=audio 9 UDP/TLS/RTP/SAVPF 111\r
+c=IN IP4 0.0.0.0\r
+a=ice-ufrag:old\r
+a=ice-pwd:oldpassword\r
+a=mid:1\r
+a=rtpmap:111 opus/48000/2\r
+";
+
+    const FRAGMENT: &str = "a=ice-ufrag:new\r
+a=ice-pwd:newpassword\r
+m=audio 9 UDP/TLS/RTP/SAVPF 111\r
+a=mid:1\r
+a=candidate:2 1 udp 2130706431 198.51.100.1 6000 typ host\r
+a=candidate:3 1 tcp 1509950719 198.51.100.1 6001 typ host tcptype passive\r
+a=end-of-candidates\r
+";
+
+    #[test]
+    fn parse() {
+        let frag = SdpFragment::parse(FRAGMENT);
+        assert_eq!(frag.ice_ufrag.as_deref(), Some("new"));
+        assert_eq!(frag.ice_pwd.as_deref(), Some("newpassword"));
+        assert_eq!(
+            frag.media.as_deref(),
+            Some("m=audio 9 UDP/TLS/RTP/SAVPF 111")
+        );
+        assert_eq!(frag.mid.as_deref(), Some("1"));
+        assert_eq!(
+            frag.candidates,
+            [
+                "candidate:2 1 udp 2130706431 198.51.100.1 6000 typ host",
+                "candidate:3 1 tcp 1509950719 198.51.100.1 6001 typ host tcptype passive",
+            ]
+        );
+        assert!(frag.end_of_candidates);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let frag = SdpFragment::parse(FRAGMENT);
+        assert_eq!(frag.to_string(), FRAGMENT);
+        assert_eq!(SdpFragment::parse(&frag.to_string()), frag);
+    }
+
+    #[test]
+    fn parse_full_sdp_takes_bundle_tag() {
+        let frag = SdpFragment::parse(OFFER);
+        assert_eq!(
+            frag.media.as_deref(),
+            Some("m=video 9 UDP/TLS/RTP/SAVPF 96")
+        );
+        assert_eq!(frag.mid.as_deref(), Some("0"));
+        assert_eq!(
+            ice_credentials(OFFER),
+            Some(("old".to_string(), "oldpassword".to_string()))
+        );
+    }
+
+    #[test]
+    fn trickle_without_credentials() {
+        let frag = SdpFragment::parse("a=end-of-candidates\r\n");
+        assert_eq!(frag.ice_ufrag, None);
+        assert!(frag.candidates.is_empty());
+        assert!(frag.end_of_candidates);
+    }
+
+    #[test]
+    fn apply_to() {
+        let applied = SdpFragment::parse(FRAGMENT).apply_to(OFFER);
+        let sections: Vec<&str> = applied.split("\r\nm=").collect();
+        assert_eq!(sections.len(), 3);
+
+        // Every media section takes the new credentials and candidates,
+        // and loses the old ones
+        for section in &sections[1..] {
+            let frag = SdpFragment::parse(&format!("m={section}"));
+            assert_eq!(frag.ice_ufrag.as_deref(), Some("new"));
+            assert_eq!(frag.ice_pwd.as_deref(), Some("newpassword"));
+            assert_eq!(frag.candidates.len(), 2);
+            assert!(frag.candidates[0].contains("198.51.100.1 6000"));
+            assert!(frag.end_of_candidates);
+        }
+        assert!(!applied.contains("192.0.2.1"));
+        assert!(!applied.contains("old"));
+        // Everything else is left alone
+        assert!(applied.contains("a=rtpmap:96 H264/90000\r\n"));
+        assert!(applied.contains("a=group:BUNDLE 0 1\r\n"));
+    }
+}
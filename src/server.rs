@@ -0,0 +1,295 @@
+use crate::{
+    auth::{AuthError, Authenticator},
+    client::WebrtcError,
+    player::{self, render_video, PlayerTx},
+    sdpfrag::{self, SdpFragment},
+    whip::{self, SessionCommand, SessionHandle},
+    NetworkConfig, PlayerConfig,
+};
+use anyhow::Result;
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap},
+    response::Response,
+    routing::{delete, post},
+    Router,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use str0m::Candidate;
+use tokio::sync::{mpsc::UnboundedSender, oneshot};
+use tracing::{error, info};
+
+struct Session {
+    commands: UnboundedSender<SessionCommand>,
+    // Authorization the session was created with, required to modify it
+    authorization: Option<String>,
+    // Entity tag PATCH requests must match with If-Match
+    etag: String,
+    // ICE ufrag from the offer, trickled sdpfrags must match it
+    remote_ufrag: Option<String>,
+}
+
+impl Session {
+    fn check_authorization(
+        &self,
+        auth: &Authenticator,
+        headers: &HeaderMap,
+    ) -> Result<(), AuthError> {
+        if auth.is_open() {
+            return Ok(());
+        }
+        match authorization(headers) {
+            None => Err(AuthError::Unauthorized),
+            given if given != self.authorization.as_deref() => Err(AuthError::Forbidden),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn new_etag() -> String {
+    format!("\"{:016x}\"", rand::random::<u64>())
+}
+
+#[derive(Clone)]
+struct ServerState {
+    tx: PlayerTx,
+    // Path WHIP offers are posted to, sessions live underneath it
+    path: String,
+    auth: Arc<Authenticator>,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    network: Arc<NetworkConfig>,
+}
+
+impl ServerState {
+    fn resource_url(&self, session_id: &str) -> String {
+        format!("{}/{}", self.path.trim_end_matches('/'), session_id)
+    }
+}
+
+fn auth_error_response(err: AuthError) -> Response<String> {
+    match err {
+        AuthError::Unauthorized => Response::builder()
+            .status(401)
+            .header(header::WWW_AUTHENTICATE, "Bearer")
+            .body(String::new())
+            .unwrap(),
+        AuthError::Forbidden => Response::builder().status(403).body(String::new()).unwrap(),
+    }
+}
+
+fn empty_response(status: u16) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .body(String::new())
+        .unwrap()
+}
+
+fn authorization(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+}
+
+async fn whip_handler(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    offer: String,
+) -> Response<String> {
+    let authorization = authorization(&headers);
+    if let Err(e) = state.auth.check(authorization, &state.path) {
+        return auth_error_response(e);
+    }
+
+    let remote_ufrag = sdpfrag::ice_credentials(&offer).map(|(ufrag, _)| ufrag);
+    let (answer, SessionHandle { commands, task }) =
+        match whip::subscribe_as_server(state.tx.clone(), offer, &state.network).await {
+            Ok(session) => session,
+            Err(WebrtcError::SdpError) => return empty_response(400),
+            Err(e) => {
+                error!("failed to create session: {:?}", e);
+                return empty_response(500);
+            }
+        };
+    let session_id = format!("{:032x}", rand::random::<u128>());
+    let etag = new_etag();
+
+    state.sessions.lock().unwrap().insert(
+        session_id.clone(),
+        Session {
+            commands,
+            authorization: authorization.map(str::to_string),
+            etag: etag.clone(),
+            remote_ufrag,
+        },
+    );
+
+    // Forget the session once its loop ends, whether deleted or disconnected
+    let (sessions, id) = (state.sessions.clone(), session_id.clone());
+    tokio::task::spawn(async move {
+        let _ = task.await;
+        sessions.lock().unwrap().remove(&id);
+        info!("session {} ended", id);
+    });
+
+    Response::builder()
+        .status(201)
+        .header("Location", state.resource_url(&session_id))
+        .header(header::ETAG, etag)
+        .body(answer)
+        .unwrap()
+}
+
+async fn delete_handler(
+    State(state): State<ServerState>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+) -> Response<String> {
+    let mut sessions = state.sessions.lock().unwrap();
+    let Some(session) = sessions.get(&session_id) else {
+        return empty_response(404);
+    };
+    if let Err(e) = session.check_authorization(&state.auth, &headers) {
+        return auth_error_response(e);
+    }
+
+    info!("deleting session {}", session_id);
+    let session = sessions.remove(&session_id).unwrap();
+    let _ = session.commands.send(SessionCommand::Close);
+    empty_response(200)
+}
+
+// Trickle ICE and ICE restarts, RFC 8840 sdpfrags carrying remote candidates
+// and, for a restart, new remote credentials
+async fn patch_handler(
+    State(state): State<ServerState>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Response<String> {
+    let frag = SdpFragment::parse(&body);
+    let commands = {
+        let sessions = state.sessions.lock().unwrap();
+        let Some(session) = sessions.get(&session_id) else {
+            return empty_response(404);
+        };
+        if let Err(e) = session.check_authorization(&state.auth, &headers) {
+            return auth_error_response(e);
+        }
+
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok());
+        if content_type != Some(sdpfrag::CONTENT_TYPE) {
+            return empty_response(415);
+        }
+
+        let if_match = headers.get(header::IF_MATCH).and_then(|v| v.to_str().ok());
+        match if_match {
+            None => return empty_response(428),
+            Some(etag) if etag != "*" && etag != session.etag => return empty_response(412),
+            _ => {}
+        }
+
+        if frag.ice_ufrag.is_some() && frag.ice_ufrag != session.remote_ufrag {
+            // New credentials are an ICE restart, which must use If-Match: *
+            if if_match != Some("*") {
+                return empty_response(422);
+            }
+            session.commands.clone()
+        } else {
+            let candidates = match frag
+                .candidates
+                .iter()
+                .map(|c| Candidate::from_sdp_string(c))
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(candidates) => candidates,
+                Err(e) => {
+                    info!("invalid trickled candidate: {:?}", e);
+                    return empty_response(400);
+                }
+            };
+
+            if !candidates.is_empty() {
+                let _ = session
+                    .commands
+                    .send(SessionCommand::RemoteCandidates(candidates));
+            }
+            return empty_response(204);
+        }
+    };
+
+    info!("ice restart for session {}", session_id);
+    let (reply, reply_rx) = oneshot::channel();
+    let remote_ufrag = frag.ice_ufrag.clone();
+    if commands
+        .send(SessionCommand::IceRestart(frag, reply))
+        .is_err()
+    {
+        return empty_response(404);
+    }
+    let local = match reply_rx.await {
+        Ok(Ok(local)) => local,
+        Ok(Err(e)) => {
+            info!("ice restart failed: {:?}", e);
+            return empty_response(400);
+        }
+        Err(_) => return empty_response(404),
+    };
+
+    // The resource changed, PATCHes against the old entity tag now fail
+    let etag = new_etag();
+    match state.sessions.lock().unwrap().get_mut(&session_id) {
+        Some(session) => {
+            session.etag = etag.clone();
+            session.remote_ufrag = remote_ufrag;
+        }
+        None => return empty_response(404),
+    }
+
+    Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, sdpfrag::CONTENT_TYPE)
+        .header(header::ETAG, etag)
+        .body(local.to_string())
+        .unwrap()
+}
+
+pub async fn play_whip(
+    listen: SocketAddr,
+    path: String,
+    auth: Authenticator,
+    network: NetworkConfig,
+    player_config: PlayerConfig,
+) -> Result<()> {
+    let (tx, rx) = player::channel();
+
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    println!("Listening for WHIP Requests on {}{}", listen, path);
+    if auth.is_open() {
+        println!("No --token or --jwt-secret given, accepting all requests");
+    }
+
+    let resource_path = format!("{}/:session", path.trim_end_matches('/'));
+    let router = Router::new()
+        .route(&path, post(whip_handler))
+        .route(&resource_path, delete(delete_handler).patch(patch_handler))
+        .with_state(ServerState {
+            tx,
+            path,
+            auth: Arc::new(auth),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            network: Arc::new(network),
+        });
+
+    tokio::task::spawn(async move {
+        axum::serve(listener, router).await.unwrap();
+    });
+
+    render_video(rx, &player_config, None);
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use super::filter::sink_graph;
+use crate::{AudioCaptureMethod, AudioConfig};
+use anyhow::Result;
+use ffmpeg_next::{filter::Graph, frame, Error};
+
+// Opus runs at 48kHz, 960 samples is one 20ms packet
+pub const SAMPLE_RATE: u32 = 48000;
+pub const FRAME_SAMPLES: usize = 960;
+
+pub trait AudioSource {
+    // Interleaved stereo s16 frames of FRAME_SAMPLES, ready for the encoder
+    fn next_frame(&mut self, out: &mut frame::Audio) -> Result<(), Error>;
+}
+
+// Audio from a filter graph, devices are opened with amovie so capture,
+// resampling and repacketising all happen in one graph
+pub struct AudioCapture {
+    graph: Graph,
+}
+
+impl AudioCapture {
+    pub fn new(method: &AudioCaptureMethod, config: &AudioConfig) -> Result<Self> {
+        let input = match method {
+            AudioCaptureMethod::Pulse => format!(
+                "amovie=filename={}:format_name=pulse",
+                config.audio_device.as_deref().unwrap_or("default")
+            ),
+            AudioCaptureMethod::Alsa => format!(
+                "amovie=filename={}:format_name=alsa",
+                config.audio_device.as_deref().unwrap_or("default")
+            ),
+            // eg. sine=frequency=440, paced like the video lavfi sources
+            AudioCaptureMethod::Lavfi => format!(
+                "{},arealtime",
+                config
+                    .audio_filter
+                    .as_deref()
+                    .unwrap_or("sine=frequency=440")
+            ),
+        };
+
+        let graph = sink_graph(
+            &format!(
+                "{input},aresample={SAMPLE_RATE},\
+                 aformat=sample_fmts=s16:channel_layouts=stereo,\
+                 asetnsamples=n={FRAME_SAMPLES}:p=0"
+            ),
+            "abuffersink",
+        )?;
+        Ok(Self { graph })
+    }
+}
+
+impl AudioSource for AudioCapture {
+    fn next_frame(&mut self, out: &mut frame::Audio) -> std::result::Result<(), Error> {
+        self.graph.get("out").unwrap().sink().frame(out)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use ffmpeg_next::{
+    format::Pixel,
+    frame,
+    software::scaling::{Context as Scaler, Flags},
+    Error,
+};
+
+// Software encoders and the player's IYUV texture want planar YUV, most
+// capture devices and some decoders don't produce it
+const OUTPUT_FORMAT: Pixel = Pixel::YUV420P;
+
+// Converts decoded frames to YUV420P, passing matching frames straight through
+#[derive(Default)]
+pub struct PixelConverter {
+    scaler: Option<Scaler>,
+    // The last output was swapped in from the input, its buffers aren't ours
+    passed_through: bool,
+}
+
+// SwsContext isn't Send in ffmpeg_next, but converters are only ever
+// driven from the single encode or render thread they are moved onto.
+unsafe impl Send for PixelConverter {}
+
+impl PixelConverter {
+    pub fn run(&mut self, input: &mut frame::Video, out: &mut frame::Video) -> Result<(), Error> {
+        if input.format() == OUTPUT_FORMAT {
+            std::mem::swap(out, input);
+            self.passed_through = true;
+            return Ok(());
+        }
+
+        let (format, width, height) = (input.format(), input.width(), input.height());
+        // (Re)create the scaler whenever the input definition changes
+        let stale = self.scaler.as_ref().map_or(true, |s| {
+            let input = s.input();
+            input.format != format || input.width != width || input.height != height
+        });
+        if stale {
+            self.scaler = Some(Scaler::get(
+                format,
+                width,
+                height,
+                OUTPUT_FORMAT,
+                width,
+                height,
+                Flags::BILINEAR,
+            )?);
+        }
+
+        // A passed through frame may still be referenced by the decoder, so
+        // never scale into it
+        if self.passed_through
+            || out.format() != OUTPUT_FORMAT
+            || out.width() != width
+            || out.height() != height
+        {
+            *out = frame::Video::new(OUTPUT_FORMAT, width, height);
+            self.passed_through = false;
+        }
+        self.scaler.as_mut().unwrap().run(input, out)?;
+        out.set_pts(input.pts());
+        unsafe {
+            (*out.as_mut_ptr()).duration = (*input.as_ptr()).duration;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use super::{PixelConverter, Source, EAGAIN};
+use crate::SourceConfig;
+
+use anyhow::{anyhow, Result};
+use ffmpeg_next::{
+    codec::Context,
+    decoder::Video as VideoDecoder,
+    format::{self, context::Input},
+    frame, media, Error, Packet, Rational,
+};
+use std::{
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+// Decodes a local media file, releasing frames in real time as their
+// timestamps come due
+pub struct MediaFile {
+    input: Input,
+    decoder: VideoDecoder,
+    stream_index: usize,
+    time_base: Rational,
+    looping: bool,
+
+    decoded: frame::Video,
+    converter: PixelConverter,
+    // pts of the first frame in the file, subtracted from all others
+    first_pts: Option<i64>,
+    // media time at which the current pass over the file started
+    loop_offset: Duration,
+    last_time: Duration,
+    frame_count: i64,
+    start: Option<Instant>,
+}
+
+impl MediaFile {
+    pub fn new(config: &SourceConfig) -> Result<Self> {
+        let path = config.device.clone().ok_or(anyhow!(
+            "file capture requires a path, pass one with --device"
+        ))?;
+        let input = format::input(&path)?;
+
+        let stream = input
+            .streams()
+            .best(media::Type::Video)
+            .ok_or(anyhow!("no video stream in {path}"))?;
+        let stream_index = stream.index();
+        let time_base = stream.time_base();
+
+        let dec_ctx = Context::from_parameters(stream.parameters())?;
+        let decoder = dec_ctx.decoder().video()?;
+
+        Ok(Self {
+            input,
+            decoder,
+            stream_index,
+            time_base,
+            looping: config.looping,
+            decoded: frame::Video::empty(),
+            converter: PixelConverter::default(),
+            first_pts: None,
+            loop_offset: Duration::ZERO,
+            last_time: Duration::ZERO,
+            frame_count: 0,
+            start: None,
+        })
+    }
+
+    // Media time of a decoded frame, relative to the first frame of the file
+    fn media_time(&mut self) -> Duration {
+        let pts = self.decoded.timestamp().unwrap_or(0);
+        let first = *self.first_pts.get_or_insert(pts);
+        let secs = (pts - first).max(0) as f64 * f64::from(self.time_base);
+        self.loop_offset + Duration::from_secs_f64(secs)
+    }
+
+    fn restart(&mut self) -> Result<(), Error> {
+        self.input.seek(0, ..)?;
+        self.decoder.flush();
+        // Continue the timeline one frame after the last frame shown
+        let frame_duration = self
+            .decoder
+            .frame_rate()
+            .filter(|r| r.numerator() > 0)
+            .map(|r| Duration::from_secs_f64(f64::from(r.invert())))
+            .unwrap_or(Duration::from_millis(33));
+        self.loop_offset = self.last_time + frame_duration;
+        self.first_pts = None;
+        Ok(())
+    }
+
+    fn decode_next(&mut self) -> Result<Duration, Error> {
+        loop {
+            match self.decoder.receive_frame(&mut self.decoded) {
+                Ok(_) => return Ok(self.media_time()),
+                Err(Error::Other { errno }) if errno == EAGAIN => {}
+                Err(Error::Eof) if self.looping => {
+                    self.restart()?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+
+            let mut p = Packet::empty();
+            match p.read(&mut self.input) {
+                Ok(_) if p.stream() == self.stream_index => self.decoder.send_packet(&p)?,
+                Ok(_) => {}
+                // drain any frames still buffered in the decoder
+                Err(Error::Eof) => self.decoder.send_eof()?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn write_frame(&mut self, out: &mut frame::Video) -> Result<(), Error> {
+        self.converter.run(&mut self.decoded, out)?;
+
+        // File timestamps restart on every loop, keep the encoder monotonic
+        out.set_pts(Some(self.frame_count));
+        self.frame_count += 1;
+        Ok(())
+    }
+}
+
+impl Source for MediaFile {
+    fn next_frame(&mut self, out: &mut frame::Video) -> Result<(), Error> {
+        let time = self.decode_next()?;
+
+        // Block until the frame is due, the same way capture devices do
+        let start = *self.start.get_or_insert(Instant::now());
+        sleep((start + time).saturating_duration_since(Instant::now()));
+
+        self.last_time = time;
+        self.write_frame(out)
+    }
+
+    fn frame_time(&self) -> Option<Duration> {
+        Some(self.last_time)
+    }
+}
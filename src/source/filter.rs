@@ -0,0 +1,55 @@
+use super::Source;
+use crate::SourceConfig;
+use anyhow::{anyhow, Result};
+use ffmpeg_next::{
+    filter::{self, Graph},
+    frame, Error,
+};
+
+// Pulls frames from any source filter graph terminating in a buffersink
+pub struct FilterGraphSource {
+    graph: Graph,
+    hw_support: bool,
+}
+
+// Build a source graph feeding the named sink, buffersink for video or
+// abuffersink for audio, which frames are then read from as "out"
+pub(super) fn sink_graph(description: &str, sink: &str) -> Result<Graph> {
+    let mut graph = filter::Graph::new();
+
+    let buffer_sink =
+        filter::find(sink).ok_or_else(|| anyhow!("Failed to find {} filter", sink))?;
+
+    graph.add(&buffer_sink, "out", "")?;
+    graph.input("out", 0)?.parse(description)?;
+    graph.validate()?;
+
+    Ok(graph)
+}
+
+impl FilterGraphSource {
+    pub fn new(description: &str, hw_support: bool) -> Result<Self> {
+        let graph = sink_graph(description, "buffersink")?;
+        Ok(Self { graph, hw_support })
+    }
+
+    // Synthetic lavfi sources, eg. testsrc2=size=1280x720:rate=60
+    pub fn lavfi(config: &SourceConfig) -> Result<Self> {
+        let filter = config.filter.as_deref().ok_or(anyhow!(
+            "lavfi capture requires a graph, pass one with --filter"
+        ))?;
+        // lavfi sources generate as fast as they can, realtime paces them
+        // to their own timestamps
+        Self::new(&format!("{filter},format=yuv420p,realtime"), false)
+    }
+}
+
+impl Source for FilterGraphSource {
+    fn hw_support(&self) -> bool {
+        self.hw_support
+    }
+    fn next_frame(&mut self, out: &mut frame::Video) -> std::result::Result<(), Error> {
+        self.graph.get("out").unwrap().sink().frame(out)?;
+        Ok(())
+    }
+}
@@ -2,11 +2,25 @@ use std::time::{Duration, Instant};
 
 use ffmpeg_next::{frame, Rational};
 
+mod audio;
 mod avfoundation;
+mod convert;
 mod dxdup;
+mod file;
+mod filter;
+mod stdin;
+mod v4l2;
+mod x11grab;
 
+pub use audio::{AudioCapture, AudioSource, SAMPLE_RATE};
 pub use avfoundation::AFScreenCapturer;
+pub use convert::PixelConverter;
 pub use dxdup::DisplayDuplicator;
+pub use file::MediaFile;
+pub use filter::FilterGraphSource;
+pub use stdin::StdinSource;
+pub use v4l2::V4l2Capturer;
+pub use x11grab::X11Capturer;
 
 pub use ffmpeg_next::util::error::{Error, EAGAIN};
 
@@ -15,6 +29,17 @@ pub trait Source {
     fn hw_support(&self) -> bool {
         false
     }
+    // Media time of the last frame returned, for sources with their own clock.
+    // Sources returning None are timestamped by wall clock.
+    fn frame_time(&self) -> Option<Duration> {
+        None
+    }
+    // Sources that can deliver frames faster than real time, like a pipe, are
+    // held back by PollSource until each frame is due. Capture devices block
+    // until their next frame instead.
+    fn needs_pacing(&self) -> bool {
+        false
+    }
 }
 
 pub struct PollSource<T> {
@@ -46,11 +71,30 @@ impl<T> PollSource<T>
 where
     T: Source,
 {
+    // The frame's own duration when the source sets one with its time base,
+    // otherwise one interval of the target rate
+    fn frame_duration(&self, frame: &frame::Video) -> Duration {
+        let (duration, time_base) = unsafe {
+            let frame = &*frame.as_ptr();
+            (frame.duration, Rational::from(frame.time_base))
+        };
+        if duration > 0 && time_base.numerator() > 0 && time_base.denominator() > 0 {
+            Duration::from_secs_f64(duration as f64 * f64::from(time_base))
+        } else {
+            Duration::from_secs_f64(f64::from(self.target_fps.invert()))
+        }
+    }
+
     pub fn next(
         &mut self,
         now: Instant,
         frame: &mut frame::Video,
     ) -> Output<Result<(), ffmpeg_next::Error>> {
+        if self.source.needs_pacing() {
+            if let Some(next) = self.next.filter(|next| *next > now) {
+                return Output::Pending(next - now);
+            }
+        }
         match self.source.next_frame(frame) {
             Err(Error::Eof) => Output::Complete,
             Err(Error::Other { errno }) if errno == EAGAIN => Output::Pending(
@@ -60,13 +104,31 @@ where
             ),
             Err(e) => Output::Item(Err(e), now - self.start),
             Ok(_) => {
-                unsafe {
-                    let d = (*frame.as_ptr()).duration * self.target_fps.numerator() as i64;
-                    self.next
-                        .replace(now + Duration::from_millis(d.try_into().unwrap()));
-                }
-                Output::Item(Ok(()), now - self.start)
+                // Stay on the frame grid unless the source has fallen behind
+                let next = self.next.unwrap_or(now) + self.frame_duration(frame);
+                self.next.replace(next.max(now));
+                let delta = self.source.frame_time().unwrap_or(now - self.start);
+                Output::Item(Ok(()), delta)
+            }
+        }
+    }
+}
+
+// Read `count` frames from a live source, waiting out EAGAIN
+#[cfg(test)]
+fn capture(source: &mut impl Source, count: usize) -> frame::Video {
+    let mut frame = frame::Video::empty();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut captured = 0;
+    while captured < count {
+        assert!(Instant::now() < deadline, "timed out capturing");
+        match source.next_frame(&mut frame) {
+            Ok(()) => captured += 1,
+            Err(Error::Other { errno }) if errno == EAGAIN => {
+                std::thread::sleep(Duration::from_millis(5))
             }
+            Err(e) => panic!("capture failed: {:?}", e),
         }
     }
+    frame
 }
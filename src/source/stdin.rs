@@ -0,0 +1,93 @@
+use super::{PixelConverter, Source};
+use crate::SourceConfig;
+
+use anyhow::{anyhow, Result};
+use ffmpeg_next::{
+    codec::Context,
+    decoder::Video as VideoDecoder,
+    format::{self, context::Input, Format},
+    frame, Dictionary, Error, Packet, Rational,
+};
+use std::ffi::CString;
+
+// Reads YUV4MPEG2, or raw frames when --size is given, from stdin
+pub struct StdinSource {
+    input: Input,
+    decoder: VideoDecoder,
+    decoded: frame::Video,
+    converter: PixelConverter,
+    rate: Rational,
+}
+
+impl StdinSource {
+    pub fn new(config: &SourceConfig) -> Result<Self> {
+        let framerate = format!("{}/1", config.framerate);
+        let mut opts = Dictionary::new();
+
+        // Raw frames carry no header, so the caller has to describe them
+        let demuxer = match &config.size {
+            Some(size) => {
+                opts.set("video_size", size);
+                opts.set(
+                    "pixel_format",
+                    config.pixel_format.as_deref().unwrap_or("yuv420p"),
+                );
+                opts.set("framerate", &framerate);
+                "rawvideo"
+            }
+            None => "yuv4mpegpipe",
+        };
+        let format = find_demuxer(demuxer).ok_or(anyhow!("missing demuxer {demuxer}"))?;
+        let input = format::open_with("pipe:0", &format, opts)?.input();
+
+        let stream = input.stream(0).unwrap();
+        // y4m headers carry their own rate, prefer it over --framerate
+        let rate = Some(stream.rate())
+            .filter(|r| r.numerator() > 0 && r.denominator() > 0)
+            .unwrap_or(Rational::new(config.framerate, 1));
+
+        let dec_ctx = Context::from_parameters(stream.parameters())?;
+        let decoder = dec_ctx.decoder().video()?;
+
+        Ok(Self {
+            input,
+            decoder,
+            decoded: frame::Video::empty(),
+            converter: PixelConverter::default(),
+            rate,
+        })
+    }
+}
+
+fn find_demuxer(name: &str) -> Option<Format> {
+    let name = CString::new(name).ok()?;
+    unsafe {
+        let ptr = ffmpeg_next::ffi::av_find_input_format(name.as_ptr());
+        (!ptr.is_null()).then(|| Format::Input(format::Input::wrap(ptr as *mut _)))
+    }
+}
+
+impl Source for StdinSource {
+    fn next_frame(&mut self, out: &mut frame::Video) -> Result<(), Error> {
+        let mut p = Packet::empty();
+        // EOF once the producer closes the pipe
+        p.read(&mut self.input)?;
+        self.decoder.send_packet(&p)?;
+        // rawvideo decodes one packet to one frame
+        self.decoder.receive_frame(&mut self.decoded)?;
+
+        self.converter.run(&mut self.decoded, out)?;
+
+        // One frame at the stream's rate, PollSource paces by it
+        unsafe {
+            (*out.as_mut_ptr()).duration = 1;
+            (*out.as_mut_ptr()).time_base = self.rate.invert().into();
+        }
+        Ok(())
+    }
+
+    // Producers may write faster than realtime
+    fn needs_pacing(&self) -> bool {
+        true
+    }
+}
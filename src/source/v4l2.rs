@@ -0,0 +1,88 @@
+use super::{PixelConverter, Source};
+use crate::SourceConfig;
+
+use anyhow::{anyhow, Result};
+use ffmpeg_next::{
+    codec::Context,
+    decoder::Video as VideoDecoder,
+    device,
+    format::{self, context::Input},
+    frame, Dictionary, Error, Packet,
+};
+
+pub struct V4l2Capturer {
+    device: Input,
+    decoder: VideoDecoder,
+    decoded: frame::Video,
+    // webcams mostly produce yuyv422 or mjpeg
+    converter: PixelConverter,
+}
+
+impl V4l2Capturer {
+    pub fn new(config: &SourceConfig) -> Result<Self> {
+        let input = device::input::video()
+            .find(|d| d.name().split(',').any(|name| name == "v4l2"))
+            .ok_or(anyhow!("missing device"))?;
+
+        let framerate = format!("{}/1", config.framerate);
+        let mut opts = Dictionary::new();
+        opts.set("framerate", &framerate);
+        if let Some(size) = &config.size {
+            opts.set("video_size", size);
+        }
+        if let Some(pixel_format) = &config.pixel_format {
+            opts.set("input_format", pixel_format);
+        }
+
+        let path = config.device.clone().unwrap_or("/dev/video0".to_string());
+        let device = format::open_with(&path, &input, opts)?.input();
+
+        let dec_ctx = Context::from_parameters(device.stream(0).unwrap().parameters())?;
+        let decoder = dec_ctx.decoder().video()?;
+
+        Ok(Self {
+            device,
+            decoder,
+            decoded: frame::Video::empty(),
+            converter: PixelConverter::default(),
+        })
+    }
+}
+
+impl Source for V4l2Capturer {
+    fn next_frame(&mut self, out: &mut frame::Video) -> Result<(), Error> {
+        let mut p = Packet::empty();
+        // EAGAIN may be returned to caller or EOF
+        p.read(&mut self.device)?;
+        self.decoder.send_packet(&p)?;
+        // rawvideo and mjpeg both decode one packet to one frame
+        self.decoder.receive_frame(&mut self.decoded)?;
+        self.converter.run(&mut self.decoded, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffmpeg_next::format::Pixel;
+
+    // Against the vivid test driver, sudo modprobe vivid then
+    // cargo test -- --ignored
+    #[test]
+    #[ignore = "needs a v4l2 device"]
+    fn captures_vivid() {
+        ffmpeg_next::init().unwrap();
+        let config = SourceConfig {
+            framerate: 10,
+            device: None,
+            size: Some("640x480".to_string()),
+            pixel_format: Some("yuyv422".to_string()),
+            looping: false,
+            filter: None,
+        };
+        let mut capturer = V4l2Capturer::new(&config).unwrap();
+        let frame = super::super::capture(&mut capturer, 3);
+        assert_eq!(frame.format(), Pixel::YUV420P);
+        assert_eq!((frame.width(), frame.height()), (640, 480));
+    }
+}
@@ -0,0 +1,87 @@
+use super::{PixelConverter, Source};
+use crate::SourceConfig;
+
+use anyhow::{anyhow, Result};
+use ffmpeg_next::{
+    codec::Context,
+    decoder::Video as VideoDecoder,
+    device,
+    format::{self, context::Input},
+    frame, Dictionary, Error, Packet,
+};
+
+pub struct X11Capturer {
+    device: Input,
+    decoder: VideoDecoder,
+    decoded: frame::Video,
+    // x11grab hands us packed BGR0
+    converter: PixelConverter,
+}
+
+impl X11Capturer {
+    pub fn new(config: &SourceConfig) -> Result<Self> {
+        let input = device::input::video()
+            .find(|d| d.name() == "x11grab")
+            .ok_or(anyhow!("missing device"))?;
+
+        let framerate = format!("{}/1", config.framerate);
+        let mut opts = Dictionary::new();
+        opts.set("framerate", &framerate);
+        opts.set("draw_mouse", "1");
+
+        // display string, eg. ":0.0+0,0", falling back to the current session
+        let display = config
+            .device
+            .clone()
+            .or_else(|| std::env::var("DISPLAY").ok())
+            .unwrap_or(":0.0".to_string());
+        let device = format::open_with(&display, &input, opts)?.input();
+
+        let dec_ctx = Context::from_parameters(device.stream(0).unwrap().parameters())?;
+        let decoder = dec_ctx.decoder().video()?;
+
+        Ok(Self {
+            device,
+            decoder,
+            decoded: frame::Video::empty(),
+            converter: PixelConverter::default(),
+        })
+    }
+}
+
+impl Source for X11Capturer {
+    fn next_frame(&mut self, out: &mut frame::Video) -> Result<(), Error> {
+        let mut p = Packet::empty();
+        // EAGAIN may be returned to caller or EOF
+        p.read(&mut self.device)?;
+        self.decoder.send_packet(&p)?;
+        // rawvideo decodes one packet to one frame
+        self.decoder.receive_frame(&mut self.decoded)?;
+        self.converter.run(&mut self.decoded, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffmpeg_next::format::Pixel;
+
+    // xvfb-run -s "-screen 0 640x480x24" cargo test -- --ignored
+    #[test]
+    #[ignore = "needs an X server"]
+    fn captures_display() {
+        ffmpeg_next::init().unwrap();
+        let config = SourceConfig {
+            framerate: 30,
+            device: None,
+            size: None,
+            pixel_format: None,
+            looping: false,
+            filter: None,
+        };
+        let mut capturer = X11Capturer::new(&config).unwrap();
+        let frame = super::super::capture(&mut capturer, 3);
+        assert_eq!(frame.format(), Pixel::YUV420P);
+        assert_eq!((frame.width(), frame.height()), (640, 480));
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use str0m::{
+    format::Codec,
+    media::{MediaData, Mid},
+    stats::{MediaIngressStats, PeerStats},
+};
+
+// Receive side of one media stream, as shown by the player
+#[derive(Clone, Debug, Default)]
+pub struct StreamStats {
+    pub codec: Option<Codec>,
+    pub kbps: f64,
+    // Fraction of packets lost, from our receiver reports
+    pub loss: Option<f32>,
+    pub jitter: Duration,
+    pub rtt: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SessionStats {
+    pub streams: Vec<StreamStats>,
+    // Everything received, media plus RTCP and ICE
+    pub total_kbps: f64,
+}
+
+#[derive(Default)]
+struct Stream {
+    stats: StreamStats,
+    last_bytes: Option<(Instant, u64)>,
+    // Arrival minus RTP time of the last packet, seconds
+    transit: Option<f64>,
+    jitter: f64,
+}
+
+// Collects str0m's periodic stats and per packet timing for a session
+pub struct StatsCollector {
+    start: Instant,
+    streams: HashMap<Mid, Stream>,
+    last_peer_bytes: Option<(Instant, u64)>,
+    total_kbps: f64,
+}
+
+fn kbps(last: &mut Option<(Instant, u64)>, now: Instant, bytes: u64) -> Option<f64> {
+    let previous = last.replace((now, bytes));
+    let (then, then_bytes) = previous?;
+    let elapsed = now.checked_duration_since(then)?.as_secs_f64();
+    (elapsed > 0.0).then(|| bytes.saturating_sub(then_bytes) as f64 * 8.0 / elapsed / 1000.0)
+}
+
+impl StatsCollector {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            streams: HashMap::new(),
+            last_peer_bytes: None,
+            total_kbps: 0.0,
+        }
+    }
+
+    pub fn media(&mut self, media: &MediaData) {
+        let stream = self.streams.entry(media.mid).or_default();
+        stream.stats.codec = Some(media.params.spec().codec);
+
+        // Interarrival jitter as in RFC 3550 section 6.4.1
+        let arrival = media
+            .network_time
+            .saturating_duration_since(self.start)
+            .as_secs_f64();
+        let transit = arrival - media.time.as_seconds();
+        if let Some(previous) = stream.transit.replace(transit) {
+            stream.jitter += ((transit - previous).abs() - stream.jitter) / 16.0;
+            stream.stats.jitter = Duration::from_secs_f64(stream.jitter);
+        }
+    }
+
+    pub fn ingress(&mut self, stats: &MediaIngressStats) {
+        let stream = self.streams.entry(stats.mid).or_default();
+        if let Some(kbps) = kbps(&mut stream.last_bytes, stats.timestamp, stats.bytes) {
+            stream.stats.kbps = kbps;
+        }
+        stream.stats.loss = stats.loss;
+        stream.stats.rtt = stats
+            .rtt
+            .map(|ms| Duration::from_secs_f64(ms as f64 / 1000.0));
+    }
+
+    pub fn peer(&mut self, stats: &PeerStats) {
+        if let Some(kbps) = kbps(&mut self.last_peer_bytes, stats.timestamp, stats.bytes_rx) {
+            self.total_kbps = kbps;
+        }
+    }
+
+    pub fn snapshot(&self) -> SessionStats {
+        let mut streams: Vec<_> = self.streams.values().map(|s| s.stats.clone()).collect();
+        // Video before audio, and stable between snapshots
+        streams.sort_by_key(|s| s.codec.map(|c| c.is_audio()));
+        SessionStats {
+            streams,
+            total_kbps: self.total_kbps,
+        }
+    }
+}
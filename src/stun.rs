@@ -0,0 +1,322 @@
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// Just enough of STUN (RFC 8489) to learn our server reflexive address, and
+// of TURN (RFC 8656) to relay through a server
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_LEN: usize = 20;
+
+pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_RESPONSE: u16 = 0x0101;
+
+pub const ALLOCATE_REQUEST: u16 = 0x0003;
+pub const ALLOCATE_RESPONSE: u16 = 0x0103;
+pub const ALLOCATE_ERROR: u16 = 0x0113;
+pub const REFRESH_REQUEST: u16 = 0x0004;
+pub const REFRESH_RESPONSE: u16 = 0x0104;
+pub const REFRESH_ERROR: u16 = 0x0114;
+pub const CREATE_PERMISSION_REQUEST: u16 = 0x0008;
+pub const CREATE_PERMISSION_RESPONSE: u16 = 0x0108;
+pub const CREATE_PERMISSION_ERROR: u16 = 0x0118;
+pub const SEND_INDICATION: u16 = 0x0016;
+pub const DATA_INDICATION: u16 = 0x0017;
+
+pub const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+pub const ATTR_USERNAME: u16 = 0x0006;
+pub const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+pub const ATTR_ERROR_CODE: u16 = 0x0009;
+pub const ATTR_LIFETIME: u16 = 0x000d;
+pub const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+pub const ATTR_DATA: u16 = 0x0013;
+pub const ATTR_REALM: u16 = 0x0014;
+pub const ATTR_NONCE: u16 = 0x0015;
+pub const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+pub const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+pub const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub method: u16,
+    pub transaction_id: [u8; 12],
+    pub attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl Message {
+    pub fn new(method: u16) -> Self {
+        Self {
+            method,
+            transaction_id: rand::random(),
+            attributes: vec![],
+        }
+    }
+
+    pub fn attribute(&self, kind: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn with(mut self, kind: u16, value: impl Into<Vec<u8>>) -> Self {
+        self.attributes.push((kind, value.into()));
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = vec![];
+        for (kind, value) in &self.attributes {
+            body.extend_from_slice(&kind.to_be_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(value);
+            // Attributes are padded to a multiple of 4 bytes
+            body.resize(body.len() + (4 - value.len() % 4) % 4, 0);
+        }
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+        buf.extend_from_slice(&self.method.to_be_bytes());
+        buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id);
+        buf.extend_from_slice(&body);
+        buf
+    }
+
+    // Encode with a trailing MESSAGE-INTEGRITY, an HMAC-SHA1 over everything
+    // before it with the length already counting the attribute
+    pub fn encode_with_integrity(&self, key: &[u8]) -> Vec<u8> {
+        let mut buf = self.encode();
+        let len = (buf.len() - HEADER_LEN + 24) as u16;
+        buf[2..4].copy_from_slice(&len.to_be_bytes());
+
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes any key size");
+        mac.update(&buf);
+        buf.extend_from_slice(&ATTR_MESSAGE_INTEGRITY.to_be_bytes());
+        buf.extend_from_slice(&20u16.to_be_bytes());
+        buf.extend_from_slice(&mac.finalize().into_bytes());
+        buf
+    }
+
+    // Length of the message at the start of a stream, once the header is in
+    pub fn frame_len(buf: &[u8]) -> Option<usize> {
+        let len = u16::from_be_bytes([*buf.get(2)?, *buf.get(3)?]) as usize;
+        (buf.len() >= HEADER_LEN).then_some(HEADER_LEN + len)
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if !is_stun(buf) {
+            return None;
+        }
+        let method = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        let mut transaction_id = [0; 12];
+        transaction_id.copy_from_slice(&buf[8..HEADER_LEN]);
+
+        let mut attributes = vec![];
+        let mut rest = buf.get(HEADER_LEN..HEADER_LEN + len)?;
+        while rest.len() >= 4 {
+            let kind = u16::from_be_bytes([rest[0], rest[1]]);
+            let value_len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            let value = rest.get(4..4 + value_len)?;
+            attributes.push((kind, value.to_vec()));
+            let padded = 4 + value_len + (4 - value_len % 4) % 4;
+            rest = rest.get(padded..).unwrap_or_default();
+        }
+
+        Some(Self {
+            method,
+            transaction_id,
+            attributes,
+        })
+    }
+
+    // Our address as seen by the server, preferring the XOR'd attribute
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        if let Some(value) = self.attribute(ATTR_XOR_MAPPED_ADDRESS) {
+            return decode_address(value, Some(&self.transaction_id));
+        }
+        decode_address(self.attribute(ATTR_MAPPED_ADDRESS)?, None)
+    }
+
+    pub fn address(&self, kind: u16) -> Option<SocketAddr> {
+        decode_address(self.attribute(kind)?, Some(&self.transaction_id))
+    }
+
+    pub fn error_code(&self) -> Option<u16> {
+        let value = self.attribute(ATTR_ERROR_CODE)?;
+        Some(*value.get(2)? as u16 * 100 + *value.get(3)? as u16)
+    }
+}
+
+// STUN shares the socket with DTLS and RTP, tell them apart by the header
+pub fn is_stun(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_LEN
+        && buf[0] & 0xc0 == 0
+        && u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) == MAGIC_COOKIE
+}
+
+// (XOR-)MAPPED-ADDRESS value, XOR'd against the cookie and transaction id if given
+pub fn decode_address(value: &[u8], xor: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let family = *value.get(1)?;
+    let mut port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    let mask = match xor {
+        Some(transaction_id) => {
+            port ^= (MAGIC_COOKIE >> 16) as u16;
+            [&MAGIC_COOKIE.to_be_bytes()[..], transaction_id].concat()
+        }
+        None => vec![0; 16],
+    };
+
+    let ip = match family {
+        0x01 => {
+            let mut octets: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            octets.iter_mut().zip(&mask).for_each(|(o, m)| *o ^= m);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        0x02 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            octets.iter_mut().zip(&mask).for_each(|(o, m)| *o ^= m);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+pub fn encode_address(addr: SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mask = [&MAGIC_COOKIE.to_be_bytes()[..], transaction_id].concat();
+    let (family, mut octets) = match addr.ip() {
+        IpAddr::V4(ip) => (0x01, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (0x02, ip.octets().to_vec()),
+    };
+    octets.iter_mut().zip(&mask).for_each(|(o, m)| *o ^= m);
+
+    let mut value = vec![0, family];
+    value.extend_from_slice(&(addr.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+    value.extend_from_slice(&octets);
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 5769 section 2.2, the IPv4 response's transaction id and address
+    const RFC5769_TRANSACTION_ID: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let message = Message::new(ALLOCATE_REQUEST)
+            .with(ATTR_REQUESTED_TRANSPORT, [17, 0, 0, 0])
+            .with(ATTR_USERNAME, "user")
+            .with(ATTR_REALM, "example.org");
+        let encoded = message.encode();
+
+        // Header plus each attribute padded to 4 bytes, 11 byte realm included
+        assert_eq!(encoded.len(), HEADER_LEN + 8 + 8 + 16);
+        assert_eq!(encoded.len() % 4, 0);
+        assert_eq!(Message::frame_len(&encoded), Some(encoded.len()));
+        assert_eq!(Message::decode(&encoded), Some(message));
+    }
+
+    #[test]
+    fn decode_rejects_non_stun() {
+        let mut encoded = Message::new(BINDING_REQUEST).encode();
+        assert!(is_stun(&encoded));
+        assert!(!is_stun(&encoded[..HEADER_LEN - 1]));
+
+        // RTP and DTLS set the top bits of the first byte
+        encoded[0] = 0x80;
+        assert!(!is_stun(&encoded));
+        assert_eq!(Message::decode(&encoded), None);
+
+        let mut encoded = Message::new(BINDING_REQUEST).encode();
+        encoded[4] ^= 0xff;
+        assert_eq!(Message::decode(&encoded), None);
+    }
+
+    #[test]
+    fn decode_truncated_attribute() {
+        let mut encoded = Message::new(BINDING_RESPONSE)
+            .with(ATTR_USERNAME, "user")
+            .encode();
+        // The attribute claims more than the message holds
+        encoded[HEADER_LEN + 3] = 16;
+        assert_eq!(Message::decode(&encoded), None);
+    }
+
+    #[test]
+    fn encode_with_integrity() {
+        let key = b"secret";
+        let message = Message::new(REFRESH_REQUEST).with(ATTR_USERNAME, "user");
+        let encoded = message.encode_with_integrity(key);
+
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(decoded.attributes.len(), 2);
+        let integrity = decoded.attribute(ATTR_MESSAGE_INTEGRITY).unwrap();
+
+        // The HMAC covers everything before the attribute, with the length
+        // already including it
+        let covered = &encoded[..encoded.len() - 24];
+        assert_eq!(
+            u16::from_be_bytes([covered[2], covered[3]]) as usize,
+            encoded.len() - HEADER_LEN
+        );
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+        mac.update(covered);
+        assert_eq!(integrity, mac.finalize().into_bytes().as_slice());
+    }
+
+    #[test]
+    fn xor_address_rfc5769() {
+        let addr: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let encoded = encode_address(addr, &RFC5769_TRANSACTION_ID);
+        assert_eq!(encoded, [0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+        assert_eq!(
+            decode_address(&encoded, Some(&RFC5769_TRANSACTION_ID)),
+            Some(addr)
+        );
+    }
+
+    #[test]
+    fn xor_address_ipv6_rfc5769() {
+        let addr: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap();
+        let encoded = encode_address(addr, &RFC5769_TRANSACTION_ID);
+        assert_eq!(
+            encoded,
+            [
+                0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25,
+                0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+            ]
+        );
+        assert_eq!(
+            decode_address(&encoded, Some(&RFC5769_TRANSACTION_ID)),
+            Some(addr)
+        );
+    }
+
+    #[test]
+    fn mapped_address_prefers_xor() {
+        let mapped: SocketAddr = "198.51.100.1:1234".parse().unwrap();
+        let plain = [&[0, 0x01][..], &1234u16.to_be_bytes(), &[198, 51, 100, 1]].concat();
+        let message = Message::new(BINDING_RESPONSE).with(ATTR_MAPPED_ADDRESS, plain);
+        assert_eq!(message.mapped_address(), Some(mapped));
+
+        let xor_mapped: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let value = encode_address(xor_mapped, &message.transaction_id);
+        let message = message.with(ATTR_XOR_MAPPED_ADDRESS, value);
+        assert_eq!(message.mapped_address(), Some(xor_mapped));
+    }
+
+    #[test]
+    fn error_code() {
+        let message = Message::new(ALLOCATE_ERROR).with(ATTR_ERROR_CODE, [0, 0, 4, 1]);
+        assert_eq!(message.error_code(), Some(401));
+        assert_eq!(Message::new(ALLOCATE_ERROR).error_code(), None);
+    }
+}
@@ -0,0 +1,446 @@
+use crate::{ice_server::IceServer, stun};
+use anyhow::{anyhow, bail, Context, Result};
+use md5::{Digest, Md5};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+use tracing::{debug, info, warn};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Permissions last 5 minutes, refresh them a minute early
+const PERMISSION_REFRESH: Duration = Duration::from_secs(240);
+// REQUESTED-TRANSPORT for a UDP relay, protocol 17 then RFFU bytes
+const TRANSPORT_UDP: [u8; 4] = [17, 0, 0, 0];
+
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+// How we reach the TURN server, STUN messages frame themselves over TCP/TLS
+enum Transport {
+    Udp(UdpSocket),
+    Stream {
+        stream: Box<dyn Stream>,
+        // Bytes read so far, reads must be cancel safe inside select!
+        buf: Vec<u8>,
+    },
+}
+
+impl Transport {
+    async fn connect(server: &IceServer) -> Result<(Self, SocketAddr)> {
+        let host_port = server
+            .host_port()
+            .ok_or(anyhow!("invalid turn server {}", server.url))?;
+        let addr = tokio::net::lookup_host(&host_port)
+            .await?
+            .next()
+            .ok_or(anyhow!("no address for {}", host_port))?;
+
+        let tls = server.url.starts_with("turns:");
+        let tcp = tls || server.url.contains("transport=tcp");
+        if !tcp {
+            let unspecified: SocketAddr = match addr {
+                SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+                SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+            };
+            let socket = UdpSocket::bind(unspecified).await?;
+            socket.connect(addr).await?;
+            return Ok((Self::Udp(socket), addr));
+        }
+
+        let tcp = TcpStream::connect(addr).await?;
+        let stream: Box<dyn Stream> = if tls {
+            let host = host_port.rsplit_once(':').map_or(&*host_port, |(h, _)| h);
+            let connector = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+            Box::new(connector.connect(host, tcp).await?)
+        } else {
+            Box::new(tcp)
+        };
+        Ok((
+            Self::Stream {
+                stream,
+                buf: vec![],
+            },
+            addr,
+        ))
+    }
+
+    async fn send(&mut self, message: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send(message).await.map(|_| ()),
+            Self::Stream { stream, .. } => stream.write_all(message).await,
+        }
+    }
+
+    // Next whole STUN message from the server
+    async fn recv(&mut self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Udp(socket) => {
+                let mut buf = vec![0; 1500];
+                let n = socket.recv(&mut buf).await?;
+                buf.truncate(n);
+                Ok(buf)
+            }
+            Self::Stream { stream, buf } => loop {
+                if let Some(len) = stun::Message::frame_len(buf).filter(|len| buf.len() >= *len) {
+                    return Ok(buf.drain(..len).collect());
+                }
+                let mut chunk = [0; 2048];
+                let n = stream.read(&mut chunk).await?;
+                if n == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            },
+        }
+    }
+}
+
+// A relay allocation on a TURN server, data goes through Send and Data
+// indications rather than channels
+pub struct TurnClient {
+    transport: Transport,
+    server: SocketAddr,
+    username: String,
+    realm: Vec<u8>,
+    nonce: Vec<u8>,
+    key: Vec<u8>,
+    relayed: SocketAddr,
+    refresh_at: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+}
+
+impl TurnClient {
+    pub async fn allocate(server: &IceServer) -> Result<Self> {
+        let (Some(username), Some(password)) = (&server.username, &server.credential) else {
+            bail!("turn server {} needs a username and credential", server.url);
+        };
+        let (mut transport, addr) = Transport::connect(server)
+            .await
+            .with_context(|| format!("Failed to connect to turn server {}", server.url))?;
+
+        // The first attempt is expected to fail, telling us the realm and nonce
+        let request = stun::Message::new(stun::ALLOCATE_REQUEST)
+            .with(stun::ATTR_REQUESTED_TRANSPORT, TRANSPORT_UDP);
+        let response = transact(&mut transport, &request.encode(), &request).await?;
+        if response.method != stun::ALLOCATE_ERROR || response.error_code() != Some(401) {
+            bail!("unexpected unauthenticated allocate response from {}", addr);
+        }
+        let realm = response
+            .attribute(stun::ATTR_REALM)
+            .ok_or(anyhow!("no realm from turn server"))?
+            .to_vec();
+        let nonce = response
+            .attribute(stun::ATTR_NONCE)
+            .ok_or(anyhow!("no nonce from turn server"))?
+            .to_vec();
+
+        // Long term credential key, MD5(username:realm:password)
+        let mut md5 = Md5::new();
+        md5.update(format!("{}:", username));
+        md5.update(&realm);
+        md5.update(format!(":{}", password));
+        let key = md5.finalize().to_vec();
+
+        let mut client = Self {
+            transport,
+            server: addr,
+            username: username.clone(),
+            realm,
+            nonce,
+            key,
+            relayed: addr,
+            refresh_at: Instant::now(),
+            permissions: HashMap::new(),
+        };
+
+        let request = client
+            .authenticated(stun::ALLOCATE_REQUEST)
+            .with(stun::ATTR_REQUESTED_TRANSPORT, TRANSPORT_UDP);
+        let encoded = request.encode_with_integrity(&client.key);
+        let response = transact(&mut client.transport, &encoded, &request).await?;
+        if response.method != stun::ALLOCATE_RESPONSE {
+            bail!(
+                "turn allocation on {} failed: {:?}",
+                addr,
+                response.error_code()
+            );
+        }
+
+        client.relayed = response
+            .address(stun::ATTR_XOR_RELAYED_ADDRESS)
+            .ok_or(anyhow!("no relayed address from turn server"))?;
+        client.schedule_refresh(&response);
+        info!("turn relay {} allocated on {}", client.relayed, addr);
+        Ok(client)
+    }
+
+    pub fn relayed_addr(&self) -> SocketAddr {
+        self.relayed
+    }
+
+    fn authenticated(&self, method: u16) -> stun::Message {
+        stun::Message::new(method)
+            .with(stun::ATTR_USERNAME, self.username.as_bytes())
+            .with(stun::ATTR_REALM, self.realm.clone())
+            .with(stun::ATTR_NONCE, self.nonce.clone())
+    }
+
+    // Refresh half way through the lifetime the server granted
+    fn schedule_refresh(&mut self, response: &stun::Message) {
+        let lifetime = response
+            .attribute(stun::ATTR_LIFETIME)
+            .and_then(|v| v.try_into().ok())
+            .map_or(600, u32::from_be_bytes);
+        self.refresh_at = Instant::now() + Duration::from_secs(lifetime as u64 / 2);
+    }
+
+    // Keep the allocation alive, called regularly from the session loop.
+    // Responses come back through recv
+    pub async fn maintain(&mut self) -> std::io::Result<()> {
+        if Instant::now() < self.refresh_at {
+            return Ok(());
+        }
+        debug!("refreshing turn allocation {}", self.relayed);
+        // Retried shortly unless a response reschedules it
+        self.refresh_at = Instant::now() + REQUEST_TIMEOUT;
+        let request = self.authenticated(stun::REFRESH_REQUEST);
+        self.transport
+            .send(&request.encode_with_integrity(&self.key))
+            .await
+    }
+
+    pub async fn send_to(&mut self, data: &[u8], peer: SocketAddr) -> std::io::Result<()> {
+        let fresh = self
+            .permissions
+            .get(&peer.ip())
+            .is_some_and(|created| created.elapsed() < PERMISSION_REFRESH);
+        if !fresh {
+            // Packets sent before the permission is in place are dropped by
+            // the server, ICE retransmits them
+            let request = self.authenticated(stun::CREATE_PERMISSION_REQUEST);
+            let address = stun::encode_address(peer, &request.transaction_id);
+            let request = request.with(stun::ATTR_XOR_PEER_ADDRESS, address);
+            self.transport
+                .send(&request.encode_with_integrity(&self.key))
+                .await?;
+            self.permissions.insert(peer.ip(), Instant::now());
+        }
+
+        let indication = stun::Message::new(stun::SEND_INDICATION);
+        let address = stun::encode_address(peer, &indication.transaction_id);
+        let indication = indication
+            .with(stun::ATTR_XOR_PEER_ADDRESS, address)
+            .with(stun::ATTR_DATA, data);
+        self.transport.send(&indication.encode()).await
+    }
+
+    // Relayed data from a peer, or None when the message was for us
+    pub async fn recv(&mut self) -> std::io::Result<Option<(SocketAddr, Vec<u8>)>> {
+        let buf = self.transport.recv().await?;
+        let Some(message) = stun::Message::decode(&buf) else {
+            return Ok(None);
+        };
+
+        match message.method {
+            stun::DATA_INDICATION => {
+                let peer = message.address(stun::ATTR_XOR_PEER_ADDRESS);
+                let data = message.attribute(stun::ATTR_DATA);
+                Ok(peer.zip(data.map(<[u8]>::to_vec)))
+            }
+            stun::REFRESH_RESPONSE => {
+                self.schedule_refresh(&message);
+                Ok(None)
+            }
+            stun::CREATE_PERMISSION_RESPONSE => Ok(None),
+            stun::REFRESH_ERROR | stun::CREATE_PERMISSION_ERROR => {
+                // Stale nonce, take the new one and retry on the next send or refresh
+                if message.error_code() == Some(438) {
+                    if let Some(nonce) = message.attribute(stun::ATTR_NONCE) {
+                        self.nonce = nonce.to_vec();
+                    }
+                    if message.method == stun::REFRESH_ERROR {
+                        self.refresh_at = Instant::now();
+                    } else {
+                        self.permissions.clear();
+                    }
+                } else {
+                    warn!(
+                        "turn request to {} failed: {:?}",
+                        self.server,
+                        message.error_code()
+                    );
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Release the allocation with a zero lifetime refresh
+    pub async fn close(&mut self) {
+        let request = self
+            .authenticated(stun::REFRESH_REQUEST)
+            .with(stun::ATTR_LIFETIME, 0u32.to_be_bytes());
+        if let Err(e) = self
+            .transport
+            .send(&request.encode_with_integrity(&self.key))
+            .await
+        {
+            debug!("failed to release turn allocation: {:?}", e);
+        }
+    }
+}
+
+// Send a request and wait for its response, resending over UDP
+async fn transact(
+    transport: &mut Transport,
+    encoded: &[u8],
+    request: &stun::Message,
+) -> Result<stun::Message> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    transport.send(encoded).await?;
+    while Instant::now() < deadline {
+        let wait = Duration::from_millis(500).min(deadline - Instant::now());
+        let Ok(buf) = tokio::time::timeout(wait, transport.recv()).await else {
+            if let Transport::Udp(_) = transport {
+                transport.send(encoded).await?;
+            }
+            continue;
+        };
+        match stun::Message::decode(&buf?) {
+            Some(response) if response.transaction_id == request.transaction_id => {
+                return Ok(response)
+            }
+            _ => continue,
+        }
+    }
+    bail!("no response from turn server")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hmac::{Hmac, Mac};
+    use sha1::Sha1;
+
+    const REALM: &str = "example.org";
+    const NONCE: &str = "f00d";
+
+    fn long_term_key(username: &str, password: &str) -> Vec<u8> {
+        Md5::digest(format!("{}:{}:{}", username, REALM, password)).to_vec()
+    }
+
+    // Whether MESSAGE-INTEGRITY, the last attribute, was made with `key`
+    fn integrity_valid(buf: &[u8], key: &[u8]) -> bool {
+        let Some(message) = stun::Message::decode(buf) else {
+            return false;
+        };
+        let Some(integrity) = message.attribute(stun::ATTR_MESSAGE_INTEGRITY) else {
+            return false;
+        };
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+        mac.update(&buf[..buf.len() - 24]);
+        mac.verify_slice(integrity).is_ok()
+    }
+
+    fn response(request: &stun::Message, method: u16) -> stun::Message {
+        stun::Message {
+            method,
+            transaction_id: request.transaction_id,
+            attributes: vec![],
+        }
+    }
+
+    // A TURN server that challenges unauthenticated requests, then grants a
+    // relay on `relayed` and refreshes it for 10 minutes
+    async fn turn_stand_in(relayed: SocketAddr, key: Vec<u8>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            while let Ok((n, source)) = socket.recv_from(&mut buf).await {
+                let Some(request) = stun::Message::decode(&buf[..n]) else {
+                    continue;
+                };
+                let authenticated = integrity_valid(&buf[..n], &key)
+                    && request.attribute(stun::ATTR_NONCE) == Some(NONCE.as_bytes());
+                let reply = match request.method {
+                    stun::ALLOCATE_REQUEST if !authenticated => {
+                        response(&request, stun::ALLOCATE_ERROR)
+                            .with(stun::ATTR_ERROR_CODE, [0, 0, 4, 1])
+                            .with(stun::ATTR_REALM, REALM)
+                            .with(stun::ATTR_NONCE, NONCE)
+                    }
+                    stun::ALLOCATE_REQUEST => response(&request, stun::ALLOCATE_RESPONSE)
+                        .with(
+                            stun::ATTR_XOR_RELAYED_ADDRESS,
+                            stun::encode_address(relayed, &request.transaction_id),
+                        )
+                        .with(
+                            stun::ATTR_XOR_MAPPED_ADDRESS,
+                            stun::encode_address(source, &request.transaction_id),
+                        )
+                        .with(stun::ATTR_LIFETIME, 600u32.to_be_bytes()),
+                    stun::REFRESH_REQUEST if !authenticated => {
+                        response(&request, stun::REFRESH_ERROR)
+                            .with(stun::ATTR_ERROR_CODE, [0, 0, 4, 1])
+                    }
+                    stun::REFRESH_REQUEST => response(&request, stun::REFRESH_RESPONSE)
+                        .with(stun::ATTR_LIFETIME, 600u32.to_be_bytes()),
+                    _ => continue,
+                };
+                let _ = socket.send_to(&reply.encode(), source).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn allocate_and_refresh() {
+        let relayed: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+        let server = turn_stand_in(relayed, long_term_key("user", "secret")).await;
+
+        let mut client = TurnClient::allocate(&IceServer {
+            url: format!("turn:{}", server),
+            username: Some("user".to_string()),
+            credential: Some("secret".to_string()),
+        })
+        .await
+        .unwrap();
+        assert_eq!(client.relayed_addr(), relayed);
+        assert_eq!(client.nonce, NONCE.as_bytes());
+        // Half the granted lifetime
+        assert!(client.refresh_at > Instant::now() + Duration::from_secs(290));
+
+        // Pretend the allocation is due for a refresh
+        client.refresh_at = Instant::now();
+        client.maintain().await.unwrap();
+        assert!(client.refresh_at <= Instant::now() + REQUEST_TIMEOUT);
+        let received = tokio::time::timeout(REQUEST_TIMEOUT, client.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, None);
+        assert!(client.refresh_at > Instant::now() + Duration::from_secs(290));
+    }
+
+    #[tokio::test]
+    async fn allocate_wrong_credential() {
+        let relayed: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+        let server = turn_stand_in(relayed, long_term_key("user", "secret")).await;
+
+        let result = TurnClient::allocate(&IceServer {
+            url: format!("turn:{}", server),
+            username: Some("user".to_string()),
+            credential: Some("wrong".to_string()),
+        })
+        .await;
+        assert!(result.is_err());
+    }
+}
@@ -1,44 +1,135 @@
 use crate::{
-    client::{Client, WebrtcEvent},
+    client::{Client, WebrtcError, WebrtcEvent},
     encoder::EncodedPacket,
+    player::{AudioChunk, PlayerTx, VideoFrame},
+    sdpfrag::SdpFragment,
+    source::SAMPLE_RATE,
+    stats::StatsCollector,
+    NetworkConfig,
 };
+use anyhow::{anyhow, Result};
 use bytes::Bytes;
-use futures::executor;
-use std::{sync::mpsc, time::Instant};
-use str0m::media::Direction as RtcDirection;
-use tokio::sync::mpsc::{error::TryRecvError, UnboundedReceiver};
-use tracing::{error, info};
+use std::{
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use str0m::{
+    format::Codec,
+    media::{Direction as RtcDirection, MediaData, MediaTime},
+    Candidate,
+};
+use tokio::{
+    sync::{
+        mpsc::{error::TryRecvError, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+
+// Requests from outside a running session loop
+#[derive(Debug)]
+pub enum SessionCommand {
+    Close,
+    // Trickled candidates from the remote peer
+    RemoteCandidates(Vec<Candidate>),
+    // ICE restart from the remote peer, replied to with our new credentials
+    IceRestart(
+        SdpFragment,
+        oneshot::Sender<Result<SdpFragment, WebrtcError>>,
+    ),
+}
+
+// How long a server side session waits for the peer to restart ICE
+const ICE_RESTART_GRACE: Duration = Duration::from_secs(30);
+
+// Maps a stream's RTP time onto the sender's wall clock using the NTP/RTP
+// pair of its last RTCP sender report. Audio and video reports share the
+// sender's clock, which is what lines them up
+#[derive(Default)]
+struct SenderClock {
+    report: Option<(Instant, MediaTime)>,
+}
+
+impl SenderClock {
+    fn capture_time(&mut self, media: &MediaData) -> Option<Instant> {
+        if let Some(info) = &media.last_sender_info {
+            self.report = Some((info.ntp_time, info.rtp_time));
+        }
+        let (ntp_time, rtp_time) = self.report?;
+        let offset = media.time.as_seconds() - rtp_time.as_seconds();
+        if offset >= 0.0 {
+            ntp_time.checked_add(Duration::from_secs_f64(offset))
+        } else {
+            ntp_time.checked_sub(Duration::from_secs_f64(-offset))
+        }
+    }
+}
+
+pub struct SessionHandle {
+    pub commands: UnboundedSender<SessionCommand>,
+    pub task: JoinHandle<()>,
+}
 
 pub async fn publish(
     publish_url: &str,
     token: Option<String>,
+    network: &NetworkConfig,
     mut packet_rx: UnboundedReceiver<EncodedPacket>,
-) {
+    mut audio_rx: Option<UnboundedReceiver<EncodedPacket>>,
+) -> Result<()> {
     info!(
         "creating client to push to {} with token: {:?}",
         publish_url, token
     );
 
-    let mut client = Client::new().await.unwrap();
+    let mut client = Client::new(network)
+        .await
+        .map_err(|e| anyhow!("failed to create webrtc client: {:?}", e))?;
     client
-        .send_whip_request(&publish_url, &token, RtcDirection::SendOnly)
+        .send_whip_request(
+            &publish_url,
+            &token,
+            RtcDirection::SendOnly,
+            audio_rx.is_some(),
+        )
         .await
-        .expect("should connect");
+        .map_err(|e| anyhow!("failed to connect to {}: {:?}", publish_url, e))?;
+    end_of_candidates(&mut client).await;
 
-    loop {
-        match client.recv().await {
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    'session: loop {
+        let event = tokio::select! {
+            event = client.recv() => event,
+            _ = &mut ctrl_c => {
+                info!("interrupted");
+                break;
+            }
+        };
+
+        match event {
             Ok(event) => match event {
                 WebrtcEvent::Disconnected => {
-                    info!("disconnected");
-                    break;
+                    if !restart_ice(&mut client).await {
+                        break;
+                    }
                 }
                 WebrtcEvent::Media(_) => {
                     panic!("Publisher incorrectly has incoming media");
                 }
-                WebrtcEvent::Continue => loop {
+                WebrtcEvent::Continue
+                | WebrtcEvent::IngressStats(_)
+                | WebrtcEvent::PeerStats(_) => loop {
+                    send_audio(&mut client, &mut audio_rx);
                     let packet = packet_rx.try_recv();
                     match packet {
-                        Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            info!("source finished");
+                            break 'session;
+                        }
                         Ok(packet) => {
                             let pts = packet.1;
                             if let Some(data) = packet.0.data() {
@@ -56,21 +147,83 @@ pub async fn publish(
             }
         }
     }
+
+    client.close().await;
+    Ok(())
 }
 
-pub async fn decode_recv_loop(mut client: Client, tx: mpsc::Sender<ffmpeg_next::frame::Video>) {
+pub async fn decode_recv_loop(
+    mut client: Client,
+    tx: PlayerTx,
+    mut commands: UnboundedReceiver<SessionCommand>,
+) {
     let codec = ffmpeg_next::decoder::find_by_name("h264").expect("H264 Decoder Available");
     let context = ffmpeg_next::codec::context::Context::new_with_codec(codec);
     let mut decoder = context.decoder().video().expect("Decoder init correctly");
+    let mut audio_decoder = opus_decoder()
+        .map_err(|e| warn!("no opus decoder, audio will not play: {:?}", e))
+        .ok();
+    let mut disconnected_at: Option<Instant> = None;
+    let (mut audio_clock, mut video_clock) = (SenderClock::default(), SenderClock::default());
+    let mut stats = StatsCollector::new();
 
-    loop {
-        match client.recv().await {
+    'session: loop {
+        if client.is_connected() {
+            disconnected_at = None;
+        } else if disconnected_at.is_some_and(|at| at.elapsed() > ICE_RESTART_GRACE) {
+            info!("no ice restart from peer, giving up");
+            break;
+        }
+
+        let event = tokio::select! {
+            event = client.recv() => event,
+            command = commands.recv() => match command {
+                Some(SessionCommand::RemoteCandidates(candidates)) => {
+                    for candidate in candidates {
+                        info!("remote candidate: {:?}", candidate);
+                        client.add_remote_candidate(candidate);
+                    }
+                    continue;
+                }
+                Some(SessionCommand::IceRestart(frag, reply)) => {
+                    info!("remote ice restart: {}", frag);
+                    let _ = reply.send(client.accept_ice_restart(&frag));
+                    continue;
+                }
+                // Owner dropped the handle, nobody can close us later
+                Some(SessionCommand::Close) | None => {
+                    info!("closing session");
+                    break;
+                }
+            },
+        };
+
+        if let Ok(WebrtcEvent::Media(media)) = &event {
+            stats.media(media);
+        }
+
+        match event {
             Ok(event) => match event {
                 WebrtcEvent::Disconnected => {
-                    info!("disconnected");
-                    break;
+                    if client.can_restart_ice() {
+                        if !restart_ice(&mut client).await {
+                            break;
+                        }
+                    } else {
+                        // Only the peer owning the resource can restart, wait for it
+                        info!("disconnected, waiting for ice restart");
+                        disconnected_at = Some(Instant::now());
+                    }
+                }
+                WebrtcEvent::Media(media) if media.params.spec().codec == Codec::Opus => {
+                    let capture_time = audio_clock.capture_time(&media);
+                    if let Some(decoder) = audio_decoder.as_mut() {
+                        decode_audio(decoder, &media.data, capture_time, &tx.audio);
+                    }
                 }
                 WebrtcEvent::Media(media) => {
+                    let capture_time = video_clock.capture_time(&media);
+                    let rtp_time = Duration::from_secs_f64(media.time.as_seconds());
                     // Decoder failures may happen, ignore them
                     match decoder.send_packet(&ffmpeg_next::Packet::borrow(&media.data)) {
                         Err(_) => continue,
@@ -79,10 +232,24 @@ pub async fn decode_recv_loop(mut client: Client, tx: mpsc::Sender<ffmpeg_next::
 
                     let mut frame = ffmpeg_next::frame::Video::empty();
                     while decoder.receive_frame(&mut frame).is_ok() {
-                        tx.send(frame).expect("pushed");
+                        let sent = tx.video.send(VideoFrame {
+                            frame,
+                            rtp_time,
+                            capture_time,
+                        });
+                        // The player window was closed, same as being told to close
+                        if sent.is_err() {
+                            info!("player closed, closing session");
+                            break 'session;
+                        }
                         frame = ffmpeg_next::frame::Video::empty();
                     }
                 }
+                WebrtcEvent::IngressStats(ingress) => {
+                    stats.ingress(&ingress);
+                    let _ = tx.stats.send(stats.snapshot());
+                }
+                WebrtcEvent::PeerStats(peer) => stats.peer(&peer),
                 WebrtcEvent::Continue => {
                     info!("Continue");
                 }
@@ -93,30 +260,167 @@ pub async fn decode_recv_loop(mut client: Client, tx: mpsc::Sender<ffmpeg_next::
             }
         }
     }
+
+    client.close().await;
+}
+
+// Send whatever audio is ready, audio ending early doesn't end the session
+fn send_audio(client: &mut Client, audio_rx: &mut Option<UnboundedReceiver<EncodedPacket>>) {
+    let Some(rx) = audio_rx else {
+        return;
+    };
+    loop {
+        match rx.try_recv() {
+            Ok(packet) => {
+                if let Some(data) = packet.0.data() {
+                    if let Err(e) = client.send_audio(Bytes::copy_from_slice(data), packet.1) {
+                        warn!("failed to send audio: {:?}", e);
+                    }
+                }
+            }
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => {
+                info!("audio source finished");
+                *audio_rx = None;
+                return;
+            }
+        }
+    }
+}
+
+// All our candidates went in the offer, let the server know there are no more
+async fn end_of_candidates(client: &mut Client) {
+    if let Err(e) = client.trickle_candidates(vec![], true).await {
+        warn!("failed to signal end of candidates: {:?}", e);
+    }
+}
+
+fn opus_decoder() -> Result<ffmpeg_next::decoder::Audio, ffmpeg_next::Error> {
+    let codec = ffmpeg_next::decoder::find(ffmpeg_next::codec::Id::OPUS)
+        .ok_or(ffmpeg_next::Error::DecoderNotFound)?;
+    let mut context = ffmpeg_next::codec::context::Context::new_with_codec(codec);
+    // Without extradata the decoder needs telling what the stream is
+    unsafe {
+        let context = &mut *context.as_mut_ptr();
+        context.sample_rate = SAMPLE_RATE as i32;
+        ffmpeg_next::ffi::av_channel_layout_default(&mut context.ch_layout, 2);
+    }
+    context.decoder().audio()
+}
+
+// Decode an Opus packet into interleaved stereo f32 for the player
+fn decode_audio(
+    decoder: &mut ffmpeg_next::decoder::Audio,
+    data: &[u8],
+    capture_time: Option<Instant>,
+    tx: &mpsc::Sender<AudioChunk>,
+) {
+    // Decoder failures may happen, ignore them
+    if decoder
+        .send_packet(&ffmpeg_next::Packet::borrow(data))
+        .is_err()
+    {
+        return;
+    }
+
+    let mut frame = ffmpeg_next::frame::Audio::empty();
+    while decoder.receive_frame(&mut frame).is_ok() {
+        let samples = interleave_stereo(&frame);
+        if !samples.is_empty() {
+            let _ = tx.send(AudioChunk {
+                samples,
+                capture_time,
+            });
+        }
+    }
+}
+
+fn interleave_stereo(frame: &ffmpeg_next::frame::Audio) -> Vec<f32> {
+    use ffmpeg_next::format::{sample::Type, Sample};
+
+    let samples = frame.samples();
+    let channels = unsafe { (*frame.as_ptr()).ch_layout.nb_channels }.max(1) as usize;
+    let read = |bytes: &[u8], index: usize, size: usize| &bytes[index * size..(index + 1) * size];
+    let sample = |channel: usize, i: usize| -> f32 {
+        match frame.format() {
+            Sample::F32(Type::Packed) => f32::from_ne_bytes(
+                read(frame.data(0), i * channels + channel, 4)
+                    .try_into()
+                    .unwrap(),
+            ),
+            Sample::F32(Type::Planar) => {
+                f32::from_ne_bytes(read(frame.data(channel), i, 4).try_into().unwrap())
+            }
+            Sample::I16(Type::Packed) => {
+                i16::from_ne_bytes(
+                    read(frame.data(0), i * channels + channel, 2)
+                        .try_into()
+                        .unwrap(),
+                ) as f32
+                    / 32768.0
+            }
+            _ => 0.0,
+        }
+    };
+
+    if !matches!(frame.format(), Sample::F32(_) | Sample::I16(Type::Packed)) {
+        warn!("unsupported decoded audio format {:?}", frame.format());
+        return vec![];
+    }
+
+    // Mono is played on both sides, anything beyond stereo is dropped
+    let right = 1.min(channels - 1);
+    (0..samples)
+        .flat_map(|i| [sample(0, i), sample(right, i)])
+        .collect()
+}
+
+// Recover from a network change on the same session, false if it can't be
+async fn restart_ice(client: &mut Client) -> bool {
+    info!("disconnected, restarting ice");
+    match client.restart_ice().await {
+        Ok(()) => true,
+        Err(e) => {
+            error!("ice restart failed: {:?}", e);
+            false
+        }
+    }
+}
+
+fn spawn_session(client: Client, tx: PlayerTx) -> SessionHandle {
+    let (commands, commands_rx) = tokio::sync::mpsc::unbounded_channel();
+    let task = tokio::task::spawn(async move {
+        decode_recv_loop(client, tx, commands_rx).await;
+    });
+    SessionHandle { commands, task }
 }
 
 pub async fn subscribe_as_client(
-    tx: mpsc::Sender<ffmpeg_next::frame::Video>,
+    tx: PlayerTx,
     publish_url: &str,
     token: Option<String>,
-) {
-    let mut client = Client::new().await.unwrap();
+    network: &NetworkConfig,
+) -> Result<SessionHandle> {
+    let mut client = Client::new(network)
+        .await
+        .map_err(|e| anyhow!("failed to create webrtc client: {:?}", e))?;
     client
-        .send_whip_request(&publish_url, &token, RtcDirection::RecvOnly)
+        .send_whip_request(&publish_url, &token, RtcDirection::RecvOnly, true)
         .await
-        .expect("should connect");
+        .map_err(|e| anyhow!("failed to connect to {}: {:?}", publish_url, e))?;
+    end_of_candidates(&mut client).await;
 
-    tokio::task::spawn(async move {
-        decode_recv_loop(client, tx).await;
-    });
+    Ok(spawn_session(client, tx))
 }
 
-pub fn subscribe_as_server(tx: mpsc::Sender<ffmpeg_next::frame::Video>, offer: String) -> String {
-    let mut client = executor::block_on(Client::new()).expect("Ok");
-    let answer = client.accept_whip_request(offer).expect("Ok");
-    tokio::task::spawn(async move {
-        decode_recv_loop(client, tx).await;
-    });
-
-    answer
+pub async fn subscribe_as_server(
+    tx: PlayerTx,
+    offer: String,
+    network: &NetworkConfig,
+) -> Result<(String, SessionHandle), WebrtcError> {
+    let mut client = Client::new(network).await?;
+    // Nobody advertises ICE servers to us here, only configured ones are used
+    client.gather_configured().await;
+    let answer = client.accept_whip_request(offer)?;
+    Ok((answer, spawn_session(client, tx)))
 }